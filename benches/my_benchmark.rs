@@ -2,13 +2,16 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use rust_quadmap_v2::entity::*;
 use rust_quadmap_v2::fixed_depth_quad_tree::*;
 use rust_quadmap_v2::enum_quadtree::QuadTree as EnumQuadTree;
+use rust_quadmap_v2::morton_quadtree::QuadTree as MortonQuadTree;
+use rust_quadmap_v2::uniform_grid::Grid;
+use rust_quadmap_v2::spatial_index::{index_model, SpatialIndex};
 
 
-//// The _main functions are called once per frame by the bencher. This is the same behavior as
-//// if they are in an outer loop (main game loop).
+// The _main functions are called once per frame by the bencher. This is the same behavior as
+// if they are in an outer loop (main game loop).
 
 
-fn no_broad_phase_main(model: &mut Vec<Entity>) {
+fn no_broad_phase_main(model: &mut [Entity]) {
 
     //update the entities
     for i in 0..model.len() {
@@ -19,51 +22,59 @@ fn no_broad_phase_main(model: &mut Vec<Entity>) {
 
 }
 
-fn fixed_depth_tree_main(model: &mut Vec<Entity>, tree: &mut QuadTree<&mut Entity>) {
+fn tree_main<Index: SpatialIndex<usize>>(model: &mut [Entity], tree: &mut Index) {
     
-    QuadTree::rebuild_from_model(tree, model);
+    index_model(tree, model);
 
-    //update the entities
-    for entity in model.iter_mut() {
-        let local_model = tree.broad_phase_mut(entity.x, entity.y);
-        update_entity_local(entity, local_model);
+    //update the entities, the tree holds their positions in the model
+    for i in 0..model.len() {
+        let local_model = tree.broad_phase(model[i].x, model[i].y);
+        update_entity_indexed(model, i, local_model.iter().copied());
     }
 
 }
 
-fn fixed_depth_tree_no_cache_main(model: &mut Vec<Entity>) {
+fn fixed_depth_tree_pairs_main(model: &mut [Entity], tree: &mut QuadTree<&mut Entity>) {
     
-    let mut tree = QuadTree::build_new_from_model(model, 128, 128);
+    //update the entities, every pair once
+    QuadTree::with_tree_boxes(tree, model, |tree| tree.for_each_pair_mut(|entity, other_entity| update_entity_pair(entity, other_entity)));
 
-    //update the entities
-    for entity in model.iter_mut() {
-        let local_model = tree.broad_phase_mut(entity.x, entity.y);
-        update_entity_local(entity, local_model);
-    }
+}
+
+fn fixed_depth_tree_parallel_main(model: &mut [Entity], tree: &mut QuadTree<&mut Entity>, threads: usize) {
+    
+    QuadTree::with_tree_boxes(tree, model, |tree| {
+        //update the entities, every leaf on one of the threads, then the pairs across leaves
+        tree.par_for_each_leaf_mut(threads, update_leaf_local);
+        tree.for_each_straddling_pair_mut(|entity, other_entity| update_entity_pair(entity, other_entity));
+    });
 
 }
 
-fn enum_tree_main(model: &mut Vec<Entity>, tree: &mut EnumQuadTree<&mut Entity>) {
+fn fixed_depth_tree_no_cache_main(model: &mut [Entity]) {
     
-    EnumQuadTree::rebuild_from_model(tree, model);
+    let mut tree: QuadTree<&mut Entity> = QuadTree::build_from_model(model, 0, 0, 128, 128);
 
-    //update the entities
-    for entity in model.iter_mut() {
-        let local_model = tree.broad_phase_mut(entity.x, entity.y);
-        update_entity_local(entity, local_model);
-    }
+    //update the entities, the tree borrows the model so they are reached through the pairs
+    tree.for_each_pair_mut(|entity, other_entity| update_entity_pair(entity, other_entity));
 
 }
 
-fn enum_tree_no_cache_main(model: &mut Vec<Entity>) {
+fn enum_tree_no_cache_main(model: &mut [Entity]) {
     
-    let mut tree = EnumQuadTree::build_new_from_model(model, 128, 128, 3);
+    let mut tree = EnumQuadTree::build_from_model(model, 0, 0, 128, 128, 3);
 
-    //update the entities
-    for entity in model.iter_mut() {
-        let local_model = tree.broad_phase_mut(entity.x, entity.y);
-        update_entity_local(entity, local_model);
-    }
+    //update the entities, the tree borrows the model so they are reached through the pairs
+    tree.for_each_pair_mut(|entity, other_entity| update_entity_pair(entity, other_entity));
+
+}
+
+fn grid_no_cache_main(model: &mut [Entity]) {
+    
+    let mut grid = Grid::build_from_model(model, 0, 0, 128, 128, 16);
+
+    //update the entities, the grid borrows the model so they are reached through the cells
+    grid.for_each_cell_mut(update_leaf_local);
 
 }
 
@@ -71,42 +82,100 @@ fn enum_tree_no_cache_main(model: &mut Vec<Entity>) {
 //TODO test with differnt depths, entity size variance, and world sizes
 
 fn bench_no_broad_phase(c: &mut Criterion) {
-    let config = ModelConfig{model_size: 1000, world_size: 128};
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
     let mut model = init_model(config);
     c.bench_function(&format!("no_broad_phase_main({:?})", config), |b| b.iter(|| no_broad_phase_main(&mut model)));
 }
 
 fn bench_fixed_depth_tree(c: &mut Criterion) {
-    let config = ModelConfig{model_size: 1000, world_size: 128};
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
+    let mut model = init_model(config);
+    let mut tree: QuadTree<_> = QuadTree::new_empty(0,0,128,128); //128x128 world, 8x8 grid, so every leaf is 16x16
+    c.bench_function(&format!("fixed_depth_tree_main({:?})", config), |b| b.iter(|| tree_main(&mut model, &mut tree)));
+}
+
+fn bench_fixed_depth_tree_pairs(c: &mut Criterion) {
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
     let mut model = init_model(config);
-    let mut tree = QuadTree::new_empty(0,0,128,128); //128x128 world, 8x8 grid, so every leaf is 16x16
-    c.bench_function(&format!("fixed_depth_tree_main({:?})", config), |b| b.iter(|| fixed_depth_tree_main(&mut model, &mut tree)));
+    let mut tree: QuadTree<_> = QuadTree::new_empty(0,0,128,128);
+    c.bench_function(&format!("fixed_depth_tree_pairs_main({:?})", config), |b| b.iter(|| fixed_depth_tree_pairs_main(&mut model, &mut tree)));
+}
+
+fn bench_fixed_depth_tree_parallel(c: &mut Criterion) {
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
+    let mut model = init_model(config);
+    let mut tree: QuadTree<_> = QuadTree::new_empty(0,0,128,128);
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    c.bench_function(&format!("fixed_depth_tree_parallel_main({:?}, {} threads)", config, threads), |b| b.iter(|| fixed_depth_tree_parallel_main(&mut model, &mut tree, threads)));
 }
 
 fn bench_fixed_depth_tree_no_cache(c: &mut Criterion) {
-    let config = ModelConfig{model_size: 1000, world_size: 128};
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
     let mut model = init_model(config);
     c.bench_function(&format!("fixed_depth_tree_no_cache_main({:?})", config), |b| b.iter(|| fixed_depth_tree_no_cache_main(&mut model)));
 }
 
 fn bench_enum_tree(c: &mut Criterion) {
-    let config = ModelConfig{model_size: 1000, world_size: 128};
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
     let mut model = init_model(config);
     let mut tree = EnumQuadTree::new_empty(0,0,128,128, 3); //128x128 world, 8x8 grid, so every leaf is 16x16
-    c.bench_function(&format!("enum_tree_main({:?})", config), |b| b.iter(|| enum_tree_main(&mut model, &mut tree)));
+    c.bench_function(&format!("enum_tree_main({:?})", config), |b| b.iter(|| tree_main(&mut model, &mut tree)));
 }
 
 fn bench_enum_tree_no_cache(c: &mut Criterion) {
-    let config = ModelConfig{model_size: 1000, world_size: 128};
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
     let mut model = init_model(config);
     c.bench_function(&format!("enum_tree_no_cache_main({:?})", config), |b| b.iter(|| enum_tree_no_cache_main(&mut model)));
 }
 
+fn bench_morton_tree(c: &mut Criterion) {
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
+    let mut model = init_model(config);
+    let mut tree = MortonQuadTree::new_empty(0,0,128,128, 3); //128x128 world, 8x8 grid, so every leaf is 16x16
+    c.bench_function(&format!("morton_tree_main({:?})", config), |b| b.iter(|| tree_main(&mut model, &mut tree)));
+}
+
+fn bench_grid(c: &mut Criterion) {
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
+    let mut model = init_model(config);
+    let mut grid = Grid::new_empty(0,0,128,128, 16); //128x128 world, 8x8 grid, so every cell is 16x16
+    c.bench_function(&format!("grid_main({:?})", config), |b| b.iter(|| tree_main(&mut model, &mut grid)));
+}
+
+fn bench_grid_no_cache(c: &mut Criterion) {
+    let config = ModelConfig{model_size: 1000, world_size: 128, ..Default::default()};
+    let mut model = init_model(config);
+    c.bench_function(&format!("grid_no_cache_main({:?})", config), |b| b.iter(|| grid_no_cache_main(&mut model)));
+}
+
+// Only the rebuild is measured here, the model is too dense for the update to finish in reasonable time
+
+fn bench_fixed_depth_tree_rebuild(c: &mut Criterion) {
+    let config = ModelConfig{model_size: 60000, world_size: 128, ..Default::default()};
+    let mut model = init_model(config);
+    let mut tree: QuadTree<_> = QuadTree::new_empty(0,0,128,128);
+    c.bench_function(&format!("fixed_depth_tree_rebuild({:?})", config), |b| {
+        //// SAFETY: The tree is only rebuilt, and never read while the model is borrowed
+        b.iter(|| unsafe {QuadTree::rebuild_from_model(&mut tree, &mut model)})
+    });
+}
+
+fn bench_fixed_depth_tree_rebuild_parallel(c: &mut Criterion) {
+    let config = ModelConfig{model_size: 60000, world_size: 128, ..Default::default()};
+    let mut model = init_model(config);
+    let mut tree: QuadTree<_> = QuadTree::new_empty(0,0,128,128);
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    c.bench_function(&format!("fixed_depth_tree_rebuild_parallel({:?}, {} threads)", config, threads), |b| {
+        //// SAFETY: The tree is only rebuilt, and never read while the model is borrowed
+        b.iter(|| unsafe {QuadTree::rebuild_from_model_parallel(&mut tree, &mut model, threads)})
+    });
+}
+
 criterion_group!(
     name = benches;
     config = Criterion::default()
         .sample_size(200);
-    targets = bench_no_broad_phase, bench_fixed_depth_tree, bench_enum_tree, bench_fixed_depth_tree_no_cache, bench_enum_tree_no_cache
+    targets = bench_no_broad_phase, bench_fixed_depth_tree, bench_enum_tree, bench_morton_tree, bench_grid, bench_fixed_depth_tree_pairs, bench_fixed_depth_tree_parallel, bench_fixed_depth_tree_no_cache, bench_enum_tree_no_cache, bench_grid_no_cache, bench_fixed_depth_tree_rebuild, bench_fixed_depth_tree_rebuild_parallel
 );
 
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,287 @@
+//! An index with an out of bounds policy, for worlds that entities can walk off.
+
+use super::rect;
+use super::bucket::for_each_pair_in;
+use super::coord::Coord;
+use super::builder::{FromBuilder, OutOfBounds, QuadTreeBuilder};
+use super::error::QuadTreeError;
+use super::nearest::KNearest;
+use super::spatial_index::{SpatialIndex, SpatialQueries};
+use super::{GetX, GetY};
+
+/// Any index, with a policy for the items inserted outside of it instead of leaving them out. The policy is
+/// taken from the builder, so `QuadTreeBuilder::new(128, 128).out_of_bounds(OutOfBounds::Overflow).build_empty()`
+/// gives a Bounded tree that keeps the entities that walked off the world in an overflow bucket, where every
+/// query still finds them. Clamped and wrapped items are stored at the position they were moved to.
+pub struct Bounded<Index, DataT, CoordT: Coord = u16> {
+    index: Index,
+    policy: OutOfBounds,
+    //Items outside the index, kept when the policy is Overflow
+    overflow: Vec<DataT>,
+    overflow_positions: Vec<(CoordT, CoordT)>,
+}
+
+/// The items whose stored position passes the test
+fn items_where<'a, DataT, CoordT: Coord>(items: &'a [DataT], positions: &'a [(CoordT, CoordT)], test: impl Fn(CoordT, CoordT) -> bool) -> impl Iterator<Item = &'a DataT> {
+    items.iter().zip(positions).filter(move |(_, &(x, y))| test(x, y)).map(|(item, _)| item)
+}
+
+/// Mutable version of items_where
+fn items_where_mut<'a, DataT, CoordT: Coord>(items: &'a mut [DataT], positions: &'a [(CoordT, CoordT)], test: impl Fn(CoordT, CoordT) -> bool) -> impl Iterator<Item = &'a mut DataT> {
+    items.iter_mut().zip(positions).filter(move |(_, &(x, y))| test(x, y)).map(|(item, _)| item)
+}
+
+impl<Index: SpatialIndex<DataT, CoordT>, DataT, CoordT: Coord> Bounded<Index, DataT, CoordT> {
+    /// Apply the policy to the items inserted outside of the index
+    pub fn with_policy(index: Index, policy: OutOfBounds) -> Self {
+        Bounded { index, policy, overflow: Vec::new(), overflow_positions: Vec::new() }
+    }
+    /// What happens to items inserted outside of the index
+    pub fn policy(&self) -> OutOfBounds {
+        self.policy
+    }
+    /// Change the policy for the next inserts. Items already in the overflow bucket stay there until cleared.
+    pub fn set_policy(&mut self, policy: OutOfBounds) {
+        self.policy = policy;
+    }
+    /// The index the items inside the bounds are stored in
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+    /// The items kept outside of the index by the Overflow policy
+    pub fn overflow(&self) -> &Vec<DataT> {
+        &self.overflow
+    }
+    /// Insert an item, applying the policy if the point is outside the index. Returns true if the item was
+    /// stored at its own position inside the index, false if the policy moved it or kept it in the overflow
+    /// bucket, or gives it back with the error if the policy left it out.
+    pub fn insert_with_policy(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<bool, (DataT, QuadTreeError<CoordT>)> {
+        let (data, error) = match self.index.try_insert(x, y, data) {
+            Ok(()) => return Ok(true),
+            Err(rejected) => rejected,
+        };
+        let QuadTreeError::OutOfBounds { rect, .. } = error;
+        match self.policy.place(rect, x, y) {
+            Some((x, y)) if self.policy == OutOfBounds::Overflow => {
+                self.overflow.push(data);
+                self.overflow_positions.push((x, y));
+                Ok(false)
+            }
+            Some((x, y)) => self.index.try_insert(x, y, data).map(|()| false),
+            None => Err((data, error)),
+        }
+    }
+}
+
+impl<Index: SpatialIndex<DataT, CoordT>, DataT, CoordT: Coord> FromBuilder<DataT, CoordT> for Bounded<Index, DataT, CoordT> {
+    fn from_builder(builder: &QuadTreeBuilder<CoordT>) -> Self {
+        Bounded::with_policy(Index::from_builder(builder), builder.out_of_bounds)
+    }
+    fn insert_point(&mut self, x: CoordT, y: CoordT, data: DataT) {
+        let _ = self.insert_with_policy(x, y, data);
+    }
+}
+
+impl<Index: SpatialIndex<DataT, CoordT>, DataT, CoordT: Coord> SpatialIndex<DataT, CoordT> for Bounded<Index, DataT, CoordT> {
+    fn clear(&mut self) {
+        self.index.clear();
+        self.overflow.clear();
+        self.overflow_positions.clear();
+    }
+    /// Insert an item, applying the policy if the point is outside the index. Returns false only if the
+    /// policy left the item out.
+    fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.insert_with_policy(x, y, data).is_ok()
+    }
+    fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        self.insert_with_policy(x, y, data).map(|_| ())
+    }
+    fn remove(&mut self, x: CoordT, y: CoordT, mut predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        let mut removed = self.index.remove(x, y, &mut predicate);
+        let mut i = 0;
+        while i < self.overflow.len() {
+            if self.overflow_positions[i] == (x, y) && predicate(&self.overflow[i]) {
+                self.overflow_positions.swap_remove(i);
+                removed.push(self.overflow.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        removed
+    }
+    /// Return the bucket of items that contains the point, or the overflow bucket for a point outside the index
+    /// when the policy is Overflow. Panics for a point outside the index under the other policies, see try_broad_phase.
+    fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.try_broad_phase(x, y).unwrap()
+    }
+    fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.try_broad_phase_mut(x, y).unwrap()
+    }
+    /// Return the bucket of items that contains the point. A point outside the index gets the overflow bucket
+    /// when the policy is Overflow, and OutOfBounds otherwise.
+    fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        match self.index.try_broad_phase(x, y) {
+            Err(_) if self.policy == OutOfBounds::Overflow => Ok(&self.overflow),
+            found => found,
+        }
+    }
+    fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        match self.index.try_broad_phase(x, y) {
+            Err(_) if self.policy == OutOfBounds::Overflow => Ok(&mut self.overflow),
+            Err(error) => Err(error),
+            Ok(_) => self.index.try_broad_phase_mut(x, y),
+        }
+    }
+    /// Return every item whose stored position is inside the rect, from the index and the overflow bucket
+    fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let mut found = self.index.query_rect(x, y, w, h);
+        found.extend(items_where(&self.overflow, &self.overflow_positions, |item_x, item_y| rect::contains((x, y, w, h), item_x, item_y)));
+        found
+    }
+    /// Clear the index, then insert every item, applying the policy to the ones outside the index. Returns
+    /// how many items the policy was applied to, whether they were moved, kept in the overflow bucket or left out.
+    fn rebuild(&mut self, items: impl IntoIterator<Item = DataT>) -> usize where DataT: GetX<CoordT> + GetY<CoordT> {
+        self.clear();
+        let mut outside = 0;
+        for item in items {
+            if !matches!(self.insert_with_policy(item.get_x(), item.get_y(), item), Ok(true)) {
+                outside += 1;
+            }
+        }
+        outside
+    }
+}
+
+/// The tree queries, which also look in the overflow bucket. Items in the overflow bucket are stored as points.
+impl<Index: SpatialQueries<DataT, CoordT>, DataT, CoordT: Coord> SpatialQueries<DataT, CoordT> for Bounded<Index, DataT, CoordT> {
+    fn query_rect_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let mut found = self.index.query_rect_mut(x, y, w, h);
+        found.extend(items_where_mut(&mut self.overflow, &self.overflow_positions, |item_x, item_y| rect::contains((x, y, w, h), item_x, item_y)));
+        found
+    }
+    fn query_box(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let mut found = self.index.query_box(x, y, w, h);
+        found.extend(items_where(&self.overflow, &self.overflow_positions, |item_x, item_y| rect::overlaps((x, y, w, h), (item_x, item_y, CoordT::ZERO, CoordT::ZERO))));
+        found
+    }
+    fn query_box_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let mut found = self.index.query_box_mut(x, y, w, h);
+        found.extend(items_where_mut(&mut self.overflow, &self.overflow_positions, |item_x, item_y| rect::overlaps((x, y, w, h), (item_x, item_y, CoordT::ZERO, CoordT::ZERO))));
+        found
+    }
+    fn query_radius(&self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let mut found = self.index.query_radius(center_x, center_y, radius);
+        found.extend(items_where(&self.overflow, &self.overflow_positions, |item_x, item_y| rect::distance_squared(center_x, center_y, item_x, item_y) <= max_d2));
+        found
+    }
+    fn query_radius_mut(&mut self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&mut DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let mut found = self.index.query_radius_mut(center_x, center_y, radius);
+        found.extend(items_where_mut(&mut self.overflow, &self.overflow_positions, |item_x, item_y| rect::distance_squared(center_x, center_y, item_x, item_y) <= max_d2));
+        found
+    }
+    /// The k closest items of the index and of the overflow bucket together
+    fn k_nearest(&self, x: CoordT, y: CoordT, k: usize) -> Vec<(f64, &DataT)> {
+        let mut best = KNearest::new(k);
+        for (d2, item) in self.index.k_nearest(x, y, k) {
+            best.offer(d2, item);
+        }
+        for (item, &(item_x, item_y)) in self.overflow.iter().zip(&self.overflow_positions) {
+            best.offer(rect::distance_squared(x, y, item_x, item_y), item);
+        }
+        best.into_vec()
+    }
+    /// The candidates of the index, and the items of the overflow bucket inside the box
+    fn broad_phase_neighbourhood(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let mut found = self.index.broad_phase_neighbourhood(x, y, w, h);
+        found.extend(items_where(&self.overflow, &self.overflow_positions, |item_x, item_y| rect::overlaps((x, y, w, h), (item_x, item_y, CoordT::ZERO, CoordT::ZERO))));
+        found
+    }
+    fn broad_phase_neighbourhood_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let mut found = self.index.broad_phase_neighbourhood_mut(x, y, w, h);
+        found.extend(items_where_mut(&mut self.overflow, &self.overflow_positions, |item_x, item_y| rect::overlaps((x, y, w, h), (item_x, item_y, CoordT::ZERO, CoordT::ZERO))));
+        found
+    }
+    /// The pairs of the index, and every pair of the overflow bucket, which is a bucket like broad_phase hands out
+    fn for_each_pair_mut(&mut self, mut f: impl FnMut(&mut DataT, &mut DataT)) {
+        self.index.for_each_pair_mut(&mut f);
+        for_each_pair_in(&mut self.overflow, &mut f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::Entity;
+    use crate::{enum_quadtree, fixed_depth_quad_tree};
+
+    type Tree = enum_quadtree::QuadTree<Entity>;
+
+    fn entity(x: u16, y: u16) -> Entity {
+        Entity { x, y, width: 1, height: 1, collision: false }
+    }
+
+    /// The original positions of the items found in the rect, which tell the items apart after a policy moved them
+    fn found_at<Index: SpatialIndex<Entity>>(bounded: &Bounded<Index, Entity>, x: u16, y: u16, w: u16, h: u16) -> Vec<(u16, u16)> {
+        let mut found: Vec<_> = bounded.query_rect(x, y, w, h).into_iter().map(|item| (item.x, item.y)).collect();
+        found.sort();
+        found
+    }
+
+    /// A Bounded tree over 0..128 with the policy, rebuilt with one entity inside it and two past its far edges.
+    /// Every policy counts the two outside.
+    fn rebuilt<Index: SpatialIndex<Entity>>(policy: OutOfBounds) -> Bounded<Index, Entity> {
+        let mut bounded: Bounded<Index, Entity> = QuadTreeBuilder::new(128, 128).out_of_bounds(policy).build_empty();
+        assert_eq!(bounded.rebuild([entity(10, 10), entity(130, 5), entity(200, 300)]), 2);
+        bounded
+    }
+
+    #[test]
+    fn reject_leaves_items_out() {
+        let mut bounded = rebuilt::<Tree>(OutOfBounds::Reject);
+        assert_eq!(found_at(&bounded, 0, 0, u16::MAX, u16::MAX), vec![(10, 10)]);
+        assert!(bounded.overflow().is_empty());
+        let error = QuadTreeError::OutOfBounds { x: 130, y: 5, rect: (0, 0, 128, 128) };
+        assert!(matches!(bounded.insert_with_policy(130, 5, entity(130, 5)), Err((item, e)) if item.x == 130 && e == error));
+        assert!(matches!(bounded.insert_with_policy(20, 20, entity(20, 20)), Ok(true)));
+    }
+
+    #[test]
+    fn clamp_moves_items_to_the_closest_edge() {
+        let mut bounded = rebuilt::<Tree>(OutOfBounds::Clamp);
+        assert_eq!(found_at(&bounded, 127, 5, 1, 1), vec![(130, 5)]);
+        assert_eq!(found_at(&bounded, 127, 127, 1, 1), vec![(200, 300)]);
+        assert!(found_at(&bounded, 128, 0, 1000, 1000).is_empty());
+        assert!(matches!(bounded.insert_with_policy(5, 1000, entity(5, 1000)), Ok(false)));
+        assert_eq!(found_at(&bounded, 5, 127, 1, 1), vec![(5, 1000)]);
+    }
+
+    #[test]
+    fn overflow_keeps_items_where_every_query_finds_them() {
+        fn check<Index: SpatialQueries<Entity>>() {
+            let mut bounded = rebuilt::<Index>(OutOfBounds::Overflow);
+            assert_eq!(bounded.overflow().len(), 2);
+            assert_eq!(found_at(&bounded, 128, 0, 100, 100), vec![(130, 5)]);
+            assert_eq!(found_at(&bounded, 0, 0, u16::MAX, u16::MAX), vec![(10, 10), (130, 5), (200, 300)]);
+            assert_eq!(bounded.query_radius(200, 290, 10).len(), 1);
+            assert_eq!(bounded.k_nearest(129, 5, 1)[0].1.x, 130);
+            assert_eq!(bounded.try_broad_phase(500, 500).map(|bucket| bucket.len()), Ok(2));
+            assert!(matches!(bounded.insert_with_policy(1000, 0, entity(1000, 0)), Ok(false)));
+            assert_eq!(bounded.remove(130, 5, |_| true).len(), 1);
+            assert_eq!(bounded.overflow().len(), 2);
+        }
+        check::<Tree>();
+        check::<fixed_depth_quad_tree::QuadTree<Entity>>();
+    }
+
+    #[test]
+    fn wrap_moves_items_around_to_the_other_side() {
+        let mut bounded = rebuilt::<Tree>(OutOfBounds::Wrap);
+        assert_eq!(found_at(&bounded, 2, 5, 1, 1), vec![(130, 5)]);
+        assert_eq!(found_at(&bounded, 72, 44, 1, 1), vec![(200, 300)]);
+        assert!(found_at(&bounded, 128, 0, 1000, 1000).is_empty());
+        assert!(matches!(bounded.insert_with_policy(256, 383, entity(256, 383)), Ok(false)));
+        assert_eq!(found_at(&bounded, 0, 127, 1, 1), vec![(256, 383)]);
+    }
+}
@@ -0,0 +1,101 @@
+//! Helpers for the buckets of items kept by the leaves, and the straddling bucket for items inserted with a
+//! bounding box that crosses the border between quadrants.
+
+use super::coord::Coord;
+use super::rect::Rect;
+
+/// Bucket for items whose box does not fit inside a single quadrant. Each item is stored once, either in
+/// the leaf that fully contains its box or in the straddling bucket of the smallest node that does, so
+/// box queries never return duplicates.
+pub struct Straddling<DataT, CoordT: Coord = u16> {
+    pub(crate) data: Vec<DataT>,
+    //The top left corner of each item's box
+    pub(crate) positions: Vec<(CoordT, CoordT)>,
+    //The width and height of each item's box
+    pub(crate) sizes: Vec<(CoordT, CoordT)>,
+}
+
+impl<DataT, CoordT: Coord> Straddling<DataT, CoordT> {
+    pub(crate) fn new() -> Self {
+        Straddling { data: Vec::new(), positions: Vec::new(), sizes: Vec::new() }
+    }
+    pub(crate) fn push(&mut self, item: Rect<CoordT>, data: DataT) {
+        self.data.push(data);
+        self.positions.push((item.0, item.1));
+        self.sizes.push((item.2, item.3));
+    }
+    pub(crate) fn clear(&mut self) {
+        self.data.clear();
+        self.positions.clear();
+        self.sizes.clear();
+    }
+    pub(crate) fn remove_where(&mut self, predicate: &mut impl FnMut(&DataT, CoordT, CoordT) -> bool) -> Vec<DataT> {
+        take_where(&mut self.data, &mut self.positions, &mut self.sizes, predicate)
+    }
+    /// Empty the bucket and give it a new item type, see recycle_vec
+    pub(crate) fn recycle<OtherT>(mut self) -> Straddling<OtherT, CoordT> {
+        self.positions.clear();
+        self.sizes.clear();
+        Straddling { data: recycle_vec(self.data), positions: self.positions, sizes: self.sizes }
+    }
+    /// The number of straddling items
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+///Remove every item of a bucket for which the predicate returns true, and return the removed items.
+///The order of the remaining items is not preserved.
+pub(crate) fn take_where<DataT, CoordT: Copy>(data: &mut Vec<DataT>, positions: &mut Vec<(CoordT, CoordT)>, sizes: &mut Vec<(CoordT, CoordT)>, predicate: &mut impl FnMut(&DataT, CoordT, CoordT) -> bool) -> Vec<DataT> {
+    let mut removed = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let (x, y) = positions[i];
+        if predicate(&data[i], x, y) {
+            positions.swap_remove(i);
+            sizes.swap_remove(i);
+            removed.push(data.swap_remove(i));
+        } else {
+            i += 1;
+        }
+    }
+    removed
+}
+
+///Call f on every unordered pair of items of a bucket once
+pub(crate) fn for_each_pair_in<DataT>(data: &mut [DataT], f: &mut impl FnMut(&mut DataT, &mut DataT)) {
+    for i in 1..data.len() {
+        let (earlier, later) = data.split_at_mut(i);
+        for other in earlier {
+            f(other, &mut later[0]);
+        }
+    }
+}
+
+///Push every unordered pair of items of a bucket once
+pub(crate) fn pairs_in<'a, DataT>(data: &'a [DataT], pairs: &mut Vec<(&'a DataT, &'a DataT)>) {
+    for (i, item) in data.iter().enumerate() {
+        pairs.extend(data[i + 1..].iter().map(|other| (item, other)));
+    }
+}
+
+///Return the index of the first item stored at exactly (x, y) for which the matcher returns true
+pub(crate) fn find_at<DataT, CoordT: PartialEq>(data: &[DataT], positions: &[(CoordT, CoordT)], x: CoordT, y: CoordT, matcher: &mut impl FnMut(&DataT) -> bool) -> Option<usize> {
+    data.iter().zip(positions).position(|(item, (item_x, item_y))| *item_x == x && *item_y == y && matcher(item))
+}
+
+///Remove the item at index and return it with its size. The last item takes its place.
+pub(crate) fn take_at<DataT, CoordT>(data: &mut Vec<DataT>, positions: &mut Vec<(CoordT, CoordT)>, sizes: &mut Vec<(CoordT, CoordT)>, index: usize) -> (DataT, (CoordT, CoordT)) {
+    positions.swap_remove(index);
+    (data.swap_remove(index), sizes.swap_remove(index))
+}
+
+///Empty a Vec and give it a new item type. When both types have the same size and alignment the items are
+///collected in place, so the allocation is kept. This is how trees of references are reused for a new borrow.
+pub(crate) fn recycle_vec<DataT, OtherT>(mut data: Vec<DataT>) -> Vec<OtherT> {
+    data.clear();
+    data.into_iter().map(|_| unreachable!("the vec was cleared")).collect()
+}
@@ -0,0 +1,256 @@
+//! A builder for trees with configurable bounds, depth and leaf capacity, that can build empty trees or
+//! fill them from any iterator of items with a position.
+
+use super::coord::Coord;
+use super::rect::{self, Rect};
+use super::{GetX, GetY};
+
+/// What happens to an item whose position is outside the bounds of the tree being built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfBounds {
+    /// Leave the item out of the tree
+    #[default]
+    Reject,
+    /// Move the item to the closest position inside the bounds
+    Clamp,
+    /// Panic with the position of the item
+    Panic,
+    /// Keep the item at its own position in an overflow bucket next to the tree, that every query also
+    /// looks in. Only a Bounded index has such a bucket, the other trees leave the item out.
+    Overflow,
+    /// Wrap the position around the bounds, so an item that leaves on one side enters on the other
+    Wrap,
+}
+
+impl OutOfBounds {
+    /// Where an item at (x, y) goes according to this policy, or None if it is left out. Points inside the
+    /// rect stay where they are, and so do the points kept in an overflow bucket.
+    pub(crate) fn place<CoordT: Coord>(self, rect: Rect<CoordT>, x: CoordT, y: CoordT) -> Option<(CoordT, CoordT)> {
+        let (rect_x, rect_y, rect_w, rect_h) = rect;
+        if rect::contains(rect, x, y) {
+            return Some((x, y));
+        }
+        match self {
+            OutOfBounds::Reject => None,
+            OutOfBounds::Overflow => Some((x, y)),
+            //there is nowhere to move the item in an empty rect
+            OutOfBounds::Clamp | OutOfBounds::Wrap if rect_w <= CoordT::ZERO || rect_h <= CoordT::ZERO => None,
+            OutOfBounds::Clamp => Some((rect::clamp(x, rect_x, rect_w), rect::clamp(y, rect_y, rect_h))),
+            OutOfBounds::Wrap => Some((rect::wrap(x, rect_x, rect_w), rect::wrap(y, rect_y, rect_h))),
+            OutOfBounds::Panic => panic!("item at ({:?}, {:?}) is outside the tree bounds {:?}", x, y, rect),
+        }
+    }
+}
+
+/// How deep the tree is, given directly or as the largest leaf size
+#[derive(Debug, Clone, Copy)]
+enum Depth<CoordT> {
+    Levels(u16),
+    LeafSize(CoordT),
+}
+
+/// Configuration for building any of the trees. The bounds default to an origin of (0, 0), the depth to 3
+/// (an 8x8 grid of leaves), the leaf capacity to 0 and out of bounds items are rejected.
+/// For example `QuadTreeBuilder::new(128, 128).leaf_size(16).build_from(model.iter().cloned())` builds an
+/// owned tree with 16x16 leaves, and the type of the tree it is assigned to picks the backend.
+#[derive(Debug, Clone, Copy)]
+pub struct QuadTreeBuilder<CoordT: Coord = u16> {
+    pub(crate) rect_x: CoordT,
+    pub(crate) rect_y: CoordT,
+    pub(crate) rect_w: CoordT,
+    pub(crate) rect_h: CoordT,
+    depth: Depth<CoordT>,
+    //Every leaf reserves room for this many items up front
+    pub(crate) leaf_capacity: usize,
+    pub(crate) out_of_bounds: OutOfBounds,
+}
+
+/// Trees that can be built by a QuadTreeBuilder
+pub trait FromBuilder<DataT, CoordT: Coord>: Sized {
+    /// Construct an empty tree covering the bounds of the builder
+    fn from_builder(builder: &QuadTreeBuilder<CoordT>) -> Self;
+    /// Insert an item at a point placed by the out of bounds policy of the builder. Points still outside the
+    /// tree are left out, unless the tree keeps them in an overflow bucket.
+    fn insert_point(&mut self, x: CoordT, y: CoordT, data: DataT);
+}
+
+impl<CoordT: Coord> QuadTreeBuilder<CoordT> {
+    /// A builder for a tree of the given width and height
+    pub fn new(rect_w: CoordT, rect_h: CoordT) -> Self {
+        QuadTreeBuilder {
+            rect_x: CoordT::ZERO,
+            rect_y: CoordT::ZERO,
+            rect_w,
+            rect_h,
+            depth: Depth::Levels(3),
+            leaf_capacity: 0,
+            out_of_bounds: OutOfBounds::Reject,
+        }
+    }
+    /// Set the top left corner of the tree
+    pub fn origin(mut self, rect_x: CoordT, rect_y: CoordT) -> Self {
+        self.rect_x = rect_x;
+        self.rect_y = rect_y;
+        self
+    }
+    /// Set the width and height of the tree
+    pub fn size(mut self, rect_w: CoordT, rect_h: CoordT) -> Self {
+        self.rect_w = rect_w;
+        self.rect_h = rect_h;
+        self
+    }
+    /// Set how many times the tree is divided into quadrants
+    pub fn depth(mut self, depth: u16) -> Self {
+        self.depth = Depth::Levels(depth);
+        self
+    }
+    /// Divide the tree until its leaves are no wider or higher than leaf_size
+    pub fn leaf_size(mut self, leaf_size: CoordT) -> Self {
+        self.depth = Depth::LeafSize(leaf_size);
+        self
+    }
+    /// Reserve room for this many items in every leaf
+    pub fn leaf_capacity(mut self, leaf_capacity: usize) -> Self {
+        self.leaf_capacity = leaf_capacity;
+        self
+    }
+    /// Set what happens to items outside the bounds of the tree
+    pub fn out_of_bounds(mut self, out_of_bounds: OutOfBounds) -> Self {
+        self.out_of_bounds = out_of_bounds;
+        self
+    }
+    /// The depth of the tree, computed from the leaf size if one was given. The backends with a limit cut it
+    /// to their own, like the MAX_DEPTH of the Morton tree, and the fixed depth tree keeps its DEPTH.
+    pub fn levels(&self) -> u16 {
+        match self.depth {
+            Depth::Levels(depth) => depth,
+            Depth::LeafSize(leaf_size) => {
+                let (mut w, mut h, mut depth) = (self.rect_w, self.rect_h, 0);
+                //stop once the quadrants can not be divided any further, like a 1 unit wide integer leaf
+                while (w > leaf_size || h > leaf_size) && w.half() > CoordT::ZERO && h.half() > CoordT::ZERO {
+                    //the right and bottom quadrants get the extra unit of odd sizes
+                    (w, h) = (w - w.half(), h - h.half());
+                    depth += 1;
+                }
+                depth
+            }
+        }
+    }
+    /// Build an empty tree
+    pub fn build_empty<DataT, Tree: FromBuilder<DataT, CoordT>>(&self) -> Tree {
+        Tree::from_builder(self)
+    }
+    /// Build a tree that owns the items. Items outside the bounds are handled by the out of bounds policy.
+    pub fn build_from<DataT: GetX<CoordT> + GetY<CoordT>, Tree: FromBuilder<DataT, CoordT>>(&self, items: impl IntoIterator<Item = DataT>) -> Tree {
+        let mut tree = Tree::from_builder(self);
+        for item in items {
+            if let Some((x, y)) = self.place(item.get_x(), item.get_y()) {
+                tree.insert_point(x, y, item);
+            }
+        }
+        tree
+    }
+    /// Build a tree of references to the items of a model. The tree borrows the model.
+    pub fn build_borrowed<'a, Entity: GetX<CoordT> + GetY<CoordT>, Tree: FromBuilder<&'a mut Entity, CoordT>>(&self, model: &'a mut [Entity]) -> Tree {
+        self.build_from(model.iter_mut())
+    }
+    /// Where an item at (x, y) goes according to the out of bounds policy, or None if it is left out
+    pub(crate) fn place(&self, x: CoordT, y: CoordT) -> Option<(CoordT, CoordT)> {
+        self.out_of_bounds.place((self.rect_x, self.rect_y, self.rect_w, self.rect_h), x, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bounded::Bounded;
+    use crate::entity::{init_model, Entity, ModelConfig};
+    use crate::spatial_index::SpatialIndex;
+    use crate::{enum_quadtree, fixed_depth_quad_tree, loose_quadtree, morton_quadtree, uniform_grid};
+
+    #[test]
+    fn levels_follow_the_leaf_size() {
+        assert_eq!(QuadTreeBuilder::new(128, 128).levels(), 3);
+        assert_eq!(QuadTreeBuilder::new(128, 128).depth(5).levels(), 5);
+        assert_eq!(QuadTreeBuilder::new(128, 128).leaf_size(16).levels(), 3);
+        assert_eq!(QuadTreeBuilder::new(128, 128).leaf_size(128).levels(), 0);
+        //100 is divided into 50, 25 and 13, as the right and bottom quadrants get the extra unit
+        assert_eq!(QuadTreeBuilder::new(100, 100).leaf_size(16).levels(), 3);
+        assert_eq!(QuadTreeBuilder::new(100, 100).leaf_size(12).levels(), 4);
+        //the larger side decides
+        assert_eq!(QuadTreeBuilder::new(100, 16).leaf_size(16).levels(), 3);
+        //a leaf size of 0 stops at 1 unit wide leaves, 100 is divided down from 50 to 1
+        assert_eq!(QuadTreeBuilder::new(100, 100).leaf_size(0).levels(), 7);
+        assert_eq!(QuadTreeBuilder::new(0u16, 0).leaf_size(0).levels(), 0);
+        assert_eq!(QuadTreeBuilder::new(1.0f64, 1.0).leaf_size(0.25).levels(), 2);
+    }
+
+    #[test]
+    fn policies_place_points_outside_the_rect() {
+        let rect = (10, 10, 100, 100);
+        let policies = [OutOfBounds::Reject, OutOfBounds::Clamp, OutOfBounds::Overflow, OutOfBounds::Wrap, OutOfBounds::Panic];
+        for policy in policies {
+            assert_eq!(policy.place(rect, 10, 109), Some((10, 109)), "{:?}", policy);
+        }
+        let outside = [(5, 50), (110, 50), (50, 250)];
+        let placed = |policy: OutOfBounds| outside.map(|(x, y)| policy.place(rect, x, y));
+        assert_eq!(placed(OutOfBounds::Reject), [None, None, None]);
+        assert_eq!(placed(OutOfBounds::Clamp), [Some((10, 50)), Some((109, 50)), Some((50, 109))]);
+        assert_eq!(placed(OutOfBounds::Overflow), [Some((5, 50)), Some((110, 50)), Some((50, 250))]);
+        assert_eq!(placed(OutOfBounds::Wrap), [Some((105, 50)), Some((10, 50)), Some((50, 50))]);
+        //there is nowhere to clamp or wrap to in an empty rect
+        assert_eq!(OutOfBounds::Clamp.place((10, 10, 0, 100), 5, 50), None);
+        assert_eq!(OutOfBounds::Wrap.place((10, 10, 100, 0), 5, 50), None);
+        let builder = QuadTreeBuilder::new(100, 100).origin(10, 10).out_of_bounds(OutOfBounds::Clamp);
+        assert_eq!(builder.place(0, 200), Some((10, 109)));
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the tree bounds")]
+    fn panic_policy_panics_on_points_outside() {
+        OutOfBounds::Panic.place((0, 0, 128, 128), 128, 0);
+    }
+
+    /// Build a backend with every entry point of the builder from a model that reaches past the bounds, and check
+    /// that every leaf reserved the leaf capacity and that the policy decided which entities are in it
+    fn check_backend<'a, Owned: SpatialIndex<Entity, u16>, Borrowed: SpatialIndex<&'a mut Entity, u16>>(name: &str, model: &'a mut [Entity], policy: OutOfBounds, expected: usize) {
+        let builder = QuadTreeBuilder::new(128, 128).leaf_size(16).leaf_capacity(40).out_of_bounds(policy);
+        let empty: Owned = builder.build_empty();
+        assert!(empty.broad_phase(5, 5).capacity() >= 40 && empty.broad_phase(127, 127).capacity() >= 40, "{}", name);
+        assert!(empty.query_rect(0, 0, 128, 128).is_empty(), "{}", name);
+        let owned: Owned = builder.build_from(model.iter().cloned());
+        assert_eq!(owned.query_rect(0, 0, u16::MAX, u16::MAX).len(), expected, "{} {:?}", name, policy);
+        let borrowed: Borrowed = builder.build_borrowed(model);
+        assert_eq!(borrowed.query_rect(0, 0, u16::MAX, u16::MAX).len(), expected, "{} {:?}", name, policy);
+    }
+
+    #[test]
+    fn every_backend_is_built_from_the_builder() {
+        let mut model = init_model(ModelConfig { model_size: 500, world_size: 160, ..Default::default() });
+        let inside = model.iter().filter(|entity| entity.x < 128 && entity.y < 128).count();
+        assert!(inside < 500);
+        for (policy, expected) in [(OutOfBounds::Reject, inside), (OutOfBounds::Clamp, 500), (OutOfBounds::Wrap, 500)] {
+            check_backend::<fixed_depth_quad_tree::QuadTree<Entity>, fixed_depth_quad_tree::QuadTree<&mut Entity>>("fixed", &mut model, policy, expected);
+            check_backend::<enum_quadtree::QuadTree<Entity>, enum_quadtree::QuadTree<&mut Entity>>("enum", &mut model, policy, expected);
+            check_backend::<loose_quadtree::QuadTree<Entity>, loose_quadtree::QuadTree<&mut Entity>>("loose", &mut model, policy, expected);
+            check_backend::<morton_quadtree::QuadTree<Entity>, morton_quadtree::QuadTree<&mut Entity>>("morton", &mut model, policy, expected);
+            check_backend::<uniform_grid::Grid<Entity>, uniform_grid::Grid<&mut Entity>>("grid", &mut model, policy, expected);
+        }
+        //only a Bounded index keeps the entities outside, the trees leave them out
+        type BoundedTree<DataT> = Bounded<enum_quadtree::QuadTree<DataT>, DataT>;
+        check_backend::<BoundedTree<Entity>, BoundedTree<&mut Entity>>("bounded", &mut model, OutOfBounds::Overflow, 500);
+        check_backend::<enum_quadtree::QuadTree<Entity>, enum_quadtree::QuadTree<&mut Entity>>("enum", &mut model, OutOfBounds::Overflow, inside);
+    }
+
+    #[test]
+    fn zero_size_worlds_build_a_grid_of_one_cell() {
+        let grid: uniform_grid::Grid<usize> = QuadTreeBuilder::new(0, 0).build_empty();
+        assert_eq!(grid.cell_size(), 1);
+        let mut grid: uniform_grid::Grid<usize, f64> = QuadTreeBuilder::new(0.0, 0.0).origin(-5.0, -5.0).depth(4).build_empty();
+        assert_eq!(grid.cell_size(), 1.0);
+        assert!(!grid.insert(-5.0, -5.0, 0));
+        assert!(grid.query_rect(-10.0, -10.0, 20.0, 20.0).is_empty());
+        let grid: uniform_grid::Grid<usize, i32> = QuadTreeBuilder::new(-10, 0).leaf_size(0).build_empty();
+        assert_eq!(grid.cell_size(), 1);
+    }
+}
@@ -0,0 +1,79 @@
+//! The coordinate types the trees can be built with.
+
+use std::fmt::Debug;
+use std::ops::{Add, Rem, Sub};
+
+/// A coordinate type for positions, sizes and rects. It is implemented for u16, u32, i32, f32 and f64,
+/// so the same trees serve tile based worlds with integer positions and physics worlds with sub-pixel ones.
+pub trait Coord: Copy + PartialOrd + Debug + Add<Output = Self> + Sub<Output = Self> + Rem<Output = Self> {
+    const ZERO: Self;
+    /// The smallest step between two different coordinates: 1 for integers and 0 for floats, which are
+    /// treated as continuous. An integer rect x..x+w has x+w-1 as its last point.
+    const STEP: Self;
+    /// A length of one unit, for the cells of a grid that has no size of its own to divide
+    const ONE: Self;
+    /// Half of a width or height, rounded down for integers
+    fn half(self) -> Self;
+    /// Used for distances, which are always measured as f64
+    fn to_f64(self) -> f64;
+    /// Subtraction that stops at the lowest value of the type instead of overflowing. Floats just subtract.
+    fn saturating_sub(self, rhs: Self) -> Self;
+    /// The largest coordinate below this one, so the last point inside a half open range ending here
+    fn prev(self) -> Self;
+    /// How far this coordinate is past start, or None if that does not fit in the type, which can happen for
+    /// signed coordinates far apart. Floats just subtract.
+    fn checked_offset(self, start: Self) -> Option<Self>;
+}
+
+macro_rules! impl_coord_int {
+    ($($t:ty),*) => {$(
+        impl Coord for $t {
+            const ZERO: Self = 0;
+            const STEP: Self = 1;
+            const ONE: Self = 1;
+            fn half(self) -> Self {
+                self / 2
+            }
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+            fn saturating_sub(self, rhs: Self) -> Self {
+                <$t>::saturating_sub(self, rhs)
+            }
+            fn prev(self) -> Self {
+                self - 1
+            }
+            fn checked_offset(self, start: Self) -> Option<Self> {
+                self.checked_sub(start)
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_coord_float {
+    ($($t:ty),*) => {$(
+        impl Coord for $t {
+            const ZERO: Self = 0.0;
+            const STEP: Self = 0.0;
+            const ONE: Self = 1.0;
+            fn half(self) -> Self {
+                self / 2.0
+            }
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+            fn saturating_sub(self, rhs: Self) -> Self {
+                self - rhs
+            }
+            fn prev(self) -> Self {
+                self.next_down()
+            }
+            fn checked_offset(self, start: Self) -> Option<Self> {
+                Some(self - start)
+            }
+        }
+    )*};
+}
+
+impl_coord_int!(u16, u32, i32);
+impl_coord_float!(f32, f64);
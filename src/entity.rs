@@ -1,61 +1,84 @@
-use super::{GetX, GetY};
+use super::{GetX, GetY, GetWidth, GetHeight};
+use super::coord::Coord;
+use super::rect;
 use rand::*;
+use rand::distributions::uniform::SampleUniform;
 
-#[derive(Debug)]
-pub struct Entity {
-    pub x: u16,
-    pub y: u16,
-    pub width: u16,
-    pub height: u16,
+/// An entity of the example game model. Coordinates default to u16, but signed or float coordinates can be
+/// used for worlds that are centred on (0, 0).
+#[derive(Debug, Clone)]
+pub struct Entity<CoordT: Coord = u16> {
+    pub x: CoordT,
+    pub y: CoordT,
+    pub width: CoordT,
+    pub height: CoordT,
     pub collision: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct ModelConfig {
+pub struct ModelConfig<CoordT: Coord = u16> {
     pub model_size: u16,
-    pub world_size: u16,
+    pub world_size: CoordT,
+    //The top left corner of the world, entities are placed in origin..origin + world_size
+    pub world_origin: (CoordT, CoordT),
+    pub entity_size: CoordT,
 }
 impl Default for ModelConfig {
     fn default() -> Self {
         Self {
             model_size: 1000,
             world_size: 128,
+            world_origin: (0, 0),
+            entity_size: 1,
         }
     }
 }
 
-pub fn init_model(config: ModelConfig) -> Vec<Entity> {
+pub fn init_model<CoordT: Coord + SampleUniform>(config: ModelConfig<CoordT>) -> Vec<Entity<CoordT>> {
     let mut model = Vec::new();
     let mut rng: rngs::StdRng = SeedableRng::seed_from_u64(42);
+    let (origin_x, origin_y) = config.world_origin;
     for _ in 0..config.model_size {
         model.push(Entity {
-            x: rng.gen_range(0..config.world_size),
-            y: rng.gen_range(0..config.world_size),
-            width: 1,
-            height: 1,
+            x: rng.gen_range(origin_x..origin_x + config.world_size),
+            y: rng.gen_range(origin_y..origin_y + config.world_size),
+            width: config.entity_size,
+            height: config.entity_size,
             collision: false,
         });
     };
     model
 }
 
-impl GetX for Entity {
-    fn get_x(&self) -> u16 {
+impl<CoordT: Coord> GetX<CoordT> for Entity<CoordT> {
+    fn get_x(&self) -> CoordT {
         self.x
     }
 }
 
-impl GetY for Entity {
-    fn get_y(&self) -> u16 {
+impl<CoordT: Coord> GetY<CoordT> for Entity<CoordT> {
+    fn get_y(&self) -> CoordT {
         self.y
     }
 }
 
-fn self_check(entity: &Entity, other_entity: &Entity) -> bool {
-    entity as *const Entity == other_entity as *const Entity
+impl<CoordT: Coord> GetWidth<CoordT> for Entity<CoordT> {
+    fn get_width(&self) -> CoordT {
+        self.width
+    }
+}
+
+impl<CoordT: Coord> GetHeight<CoordT> for Entity<CoordT> {
+    fn get_height(&self) -> CoordT {
+        self.height
+    }
+}
+
+fn self_check<CoordT: Coord>(entity: &Entity<CoordT>, other_entity: &Entity<CoordT>) -> bool {
+    std::ptr::eq(entity, other_entity)
 }
 
-pub fn update_entity_local(entity: &mut Entity, local_model: &mut [&mut Entity]) {
+pub fn update_entity_local<CoordT: Coord>(entity: &mut Entity<CoordT>, local_model: &mut [&mut Entity<CoordT>]) {
     for other_entity in local_model {
         if self_check(entity, other_entity) {continue}
         if is_coliding(entity, other_entity) {
@@ -64,8 +87,80 @@ pub fn update_entity_local(entity: &mut Entity, local_model: &mut [&mut Entity])
     }
 }
 
+/// Run update_entity_local for every entity of a leaf against the rest of the leaf, for the leaves handed out
+/// by par_for_each_leaf_mut. Each entity is swapped to the end in turn, so it is never in its own local model.
+pub fn update_leaf_local<CoordT: Coord>(leaf: &mut [&mut Entity<CoordT>]) {
+    let Some(last) = leaf.len().checked_sub(1) else { return };
+    for i in 0..leaf.len() {
+        leaf.swap(i, last);
+        let (entity, local_model) = leaf.split_last_mut().unwrap();
+        update_entity_local(entity, local_model);
+        leaf.swap(i, last);
+    }
+}
+
+/// Mark both entities of a candidate pair if they collide, for the pairs of for_each_pair_mut. Every pair is
+/// handed out once, so unlike update_entity_local there is no need to skip the entity itself.
+pub fn update_entity_pair<CoordT: Coord>(entity: &mut Entity<CoordT>, other_entity: &mut Entity<CoordT>) {
+    if is_coliding(entity, other_entity) {
+        entity.collision = true;
+        other_entity.collision = true;
+    }
+}
+
+/// Like update_entity_local, but for the candidates returned by broad_phase_neighbourhood
+pub fn update_entity_candidates<'a, CoordT: Coord + 'a>(entity: &mut Entity<CoordT>, candidates: impl IntoIterator<Item = &'a Entity<CoordT>>) {
+    for other_entity in candidates {
+        if self_check(entity, other_entity) {continue}
+        if is_coliding(entity, other_entity) {
+            entity.collision = true;
+        }
+    }
+}
+
+/// Like update_entity_candidates, for an index that holds the positions of the entities in the model instead
+/// of references to them. The entity is reached through the model, and skipped among the candidates by its index.
+pub fn update_entity_indexed<CoordT: Coord>(model: &mut [Entity<CoordT>], index: usize, candidates: impl IntoIterator<Item = usize>) {
+    let entity = &model[index];
+    if candidates.into_iter().any(|other| other != index && is_coliding(entity, &model[other])) {
+        model[index].collision = true;
+    }
+}
+
+/// The box to pass to broad_phase_neighbourhood for this entity. Entities are stored by their top left
+/// corner, so the box is grown up and left by the entity's own size to find entities of the same size
+/// that overlap it from a neighbouring leaf. Unsigned coordinates stop growing at 0.
+pub fn neighbourhood<CoordT: Coord>(entity: &Entity<CoordT>) -> (CoordT, CoordT, CoordT, CoordT) {
+    let x = entity.x.saturating_sub(entity.width.saturating_sub(CoordT::STEP));
+    let y = entity.y.saturating_sub(entity.height.saturating_sub(CoordT::STEP));
+    (x, y, entity.x - x + entity.width, entity.y - y + entity.height)
+}
+
+/// Like update_entity_candidates, on a world that wraps around at its edges, see is_coliding_wrapped
+pub fn update_entity_candidates_wrapped<'a, CoordT: Coord + 'a>(entity: &mut Entity<CoordT>, candidates: impl IntoIterator<Item = &'a Entity<CoordT>>, world: (CoordT, CoordT, CoordT, CoordT)) {
+    for other_entity in candidates {
+        if self_check(entity, other_entity) {continue}
+        if is_coliding_wrapped(entity, other_entity, world) {
+            entity.collision = true;
+        }
+    }
+}
+
+/// Like neighbourhood, on a world that wraps around at its edges. The box starts inside the world, and goes
+/// on from the far edges when it is passed to the wrapped queries, so it also grows past the top left corner.
+pub fn neighbourhood_wrapped<CoordT: Coord>(entity: &Entity<CoordT>, world: (CoordT, CoordT, CoordT, CoordT)) -> (CoordT, CoordT, CoordT, CoordT) {
+    let (world_x, world_y, world_w, world_h) = world;
+    if world_w <= CoordT::ZERO || world_h <= CoordT::ZERO {
+        return neighbourhood(entity);
+    }
+    let (grow_x, grow_y) = (entity.width.saturating_sub(CoordT::STEP), entity.height.saturating_sub(CoordT::STEP));
+    let x = rect::wrap_back(entity.x, grow_x, world_x, world_w);
+    let y = rect::wrap_back(entity.y, grow_y, world_y, world_h);
+    (x, y, grow_x + entity.width, grow_y + entity.height)
+}
+
 #[allow(dead_code)]
-pub fn update_entity_global(entity: &mut Entity, model: &mut [Entity]) {
+pub fn update_entity_global<CoordT: Coord>(entity: &mut Entity<CoordT>, model: &mut [Entity<CoordT>]) {
     for other_entity in model {
         if self_check(entity, other_entity) {continue}
         if is_coliding(entity, other_entity) {
@@ -74,10 +169,53 @@ pub fn update_entity_global(entity: &mut Entity, model: &mut [Entity]) {
     }
 }
 
-pub fn is_coliding(entity: &Entity, other_entity: &Entity) -> bool {
+pub fn is_coliding<CoordT: Coord>(entity: &Entity<CoordT>, other_entity: &Entity<CoordT>) -> bool {
     entity.x < other_entity.x + other_entity.width &&
     entity.x + entity.width > other_entity.x &&
     entity.y < other_entity.y + other_entity.height &&
     entity.y + entity.height > other_entity.y
 }
 
+/// Like is_coliding, on a world that wraps around at its edges, so an entity at the right edge collides with
+/// one at the left edge. Positions outside the world are wrapped into it first. An empty world has no collisions.
+/// Entities cover x..x+width and y..y+height like elsewhere, so they collide when they overlap, not when they
+/// only touch. In a 128 wide world an entity at x=127 reaches across the edge to x=0 once it is 2 wide, while
+/// 1 wide entities at x=127 and x=0 only meet at the seam and do not collide.
+pub fn is_coliding_wrapped<CoordT: Coord>(entity: &Entity<CoordT>, other_entity: &Entity<CoordT>, world: (CoordT, CoordT, CoordT, CoordT)) -> bool {
+    let (world_x, world_y, world_w, world_h) = world;
+    if world_w <= CoordT::ZERO || world_h <= CoordT::ZERO {
+        return false;
+    }
+    rect::spans_overlap_wrapped(entity.x, entity.width, other_entity.x, other_entity.width, world_x, world_w) &&
+    rect::spans_overlap_wrapped(entity.y, entity.height, other_entity.y, other_entity.height, world_y, world_h)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(x: u16, y: u16, size: u16) -> Entity {
+        Entity { x, y, width: size, height: size, collision: false }
+    }
+
+    #[test]
+    fn wrapped_collisions_cross_the_edges() {
+        let world = (0, 0, 128, 128);
+        //an entity at x=127 covers 127..129, which wraps around to x=0
+        assert!(is_coliding_wrapped(&entity(127, 0, 2), &entity(0, 0, 1), world));
+        assert!(is_coliding_wrapped(&entity(0, 127, 2), &entity(0, 0, 1), world));
+        assert!(is_coliding_wrapped(&entity(127, 127, 2), &entity(0, 0, 1), world));
+        //127..128 and 0..1 meet at the seam of the world but do not overlap, like touching entities elsewhere
+        assert!(!is_coliding_wrapped(&entity(127, 0, 1), &entity(0, 0, 1), world));
+        assert!(!is_coliding_wrapped(&entity(0, 127, 1), &entity(0, 0, 1), world));
+        assert!(is_coliding_wrapped(&entity(0, 0, 1), &entity(127, 127, 2), world));
+        assert!(!is_coliding_wrapped(&entity(0, 0, 1), &entity(0, 0, 1), (0, 0, 0, 128)));
+    }
+
+    #[test]
+    fn wrapped_neighbourhood_grows_past_the_top_left_corner() {
+        let world = (0, 0, 128, 128);
+        assert_eq!(neighbourhood_wrapped(&entity(0, 10, 4), world), (125, 7, 7, 7));
+        assert_eq!(neighbourhood_wrapped(&entity(130, 0, 1), world), (2, 0, 1, 1));
+    }
+}
@@ -1,41 +1,64 @@
+use super::rect::{self, divide_into_4, quadrant_of, Rect};
+use super::nearest::{Closest, KNearest};
+use super::bucket::{find_at, for_each_pair_in, pairs_in, recycle_vec, take_at, take_where, Straddling};
+use super::coord::Coord;
+use super::builder::{FromBuilder, OutOfBounds, QuadTreeBuilder};
+use super::spatial_index::{SpatialIndex, SpatialQueries};
+use super::error::{check_bounds, QuadTreeError};
+use std::collections::BinaryHeap;
+
 /// The QuadTree is a recursive data structure that divides a rectangle into 4 quadrants, each containing other quadrants, or a leaf
-pub enum QuadTree<DataT> {
+pub enum QuadTree<DataT, CoordT: Coord = u16> {
     Leaf {
         //TODO DataT: GetX + GetY
         data: Vec<DataT>,
-        positions: Vec<(u16, u16)>,
-        rect_x: u16,
-        rect_y: u16,
-        rect_w: u16,
-        rect_h: u16,
+        positions: Vec<(CoordT, CoordT)>,
+        //Width and height of each item, items inserted as a point have a size of 0
+        sizes: Vec<(CoordT, CoordT)>,
+        rect_x: CoordT,
+        rect_y: CoordT,
+        rect_w: CoordT,
+        rect_h: CoordT,
+        //None for fixed depth trees, otherwise the leaf turns into Quads once it holds too many items
+        split: Option<SplitRule>,
     },
-    Quads([Box<QuadTree<DataT>>; 4]),
+    //Items whose box crosses the border between the 4 quadrants are kept next to them
+    Quads([Box<QuadTree<DataT, CoordT>>; 4], Straddling<DataT, CoordT>),
+}
+
+/// Controls when an adaptive leaf is split into 4 quadrants, and when 4 sibling leaves are merged back
+#[derive(Debug, Clone, Copy)]
+pub struct SplitRule {
+    /// A leaf splits once it holds more than this many items
+    pub capacity: usize,
+    /// How many more times the leaf is allowed to split. This guards against endless splitting when
+    /// many items share the same position.
+    pub depth_left: u16,
 }
 
-///Split a rect into 4 quadrants. This is a utility function used by the QuadTree constructor
-fn divide_into_4(rect_x: u16, rect_y: u16, rect_w: u16, rect_h: u16) -> [(u16, u16, u16, u16); 4] {
-    let half_w = rect_w / 2;
-    let half_h = rect_h / 2;
-    [
-        (rect_x, rect_y, half_w, half_h),
-        (rect_x + half_w, rect_y, half_w, half_h),
-        (rect_x, rect_y + half_h, half_w, half_h),
-        (rect_x + half_w, rect_y + half_h, half_w, half_h)
-    ]
+/// What happened to the item looked up by relocate
+enum Relocated<DataT, CoordT> {
+    NotFound,
+    //The item stays in its leaf and only its position was changed
+    InPlace,
+    //The item was taken out of the tree so it can be inserted again, with its size
+    Taken(DataT, (CoordT, CoordT)),
 }
 
 /// A QuadTree is a recursive data structure that divides a rectangle into 4 quadrants, each containing other quadrants, or a leaf
-impl<DataT> QuadTree<DataT> {
+impl<DataT, CoordT: Coord> QuadTree<DataT, CoordT> {
     /// Construct 4 empty quadrants, each containing other quadrants, or a leaf
-    pub fn new_empty(rect_x: u16, rect_y: u16, rect_w: u16, rect_h: u16, depth: u16) -> Self {
+    pub fn new_empty(rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT, depth: u16) -> Self {
         if depth == 0 {
             QuadTree::Leaf {
                 data: Vec::new(),
                 positions: Vec::new(),
+                sizes: Vec::new(),
                 rect_x,
                 rect_y,
                 rect_w,
                 rect_h,
+                split: None,
             }
         } else {
             let rect = divide_into_4(rect_x, rect_y, rect_w, rect_h);
@@ -44,177 +67,1099 @@ impl<DataT> QuadTree<DataT> {
                 Box::new(QuadTree::new_empty(rect[1].0, rect[1].1, rect[1].2, rect[1].3, depth - 1)),
                 Box::new(QuadTree::new_empty(rect[2].0, rect[2].1, rect[2].2, rect[2].3, depth - 1)),
                 Box::new(QuadTree::new_empty(rect[3].0, rect[3].1, rect[3].2, rect[3].3, depth - 1)),
-            ])
+            ], Straddling::new())
         }
     }
-    /// Remove all points from all leaves
+    /// Construct a single leaf that splits into quadrants whenever it holds more than `capacity` items,
+    /// at most `max_depth` times. Sibling leaves are merged back together by `merge_sparse`.
+    pub fn new_adaptive(rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT, capacity: usize, max_depth: u16) -> Self {
+        QuadTree::Leaf {
+            data: Vec::new(),
+            positions: Vec::new(),
+            sizes: Vec::new(),
+            rect_x,
+            rect_y,
+            rect_w,
+            rect_h,
+            split: Some(SplitRule { capacity, depth_left: max_depth }),
+        }
+    }
+    /// Remove all points from all leaves. This does not merge adaptive leaves, so the shape of the tree
+    /// is kept for the next rebuild.
     pub fn clear(&mut self) {
         match self {
-            QuadTree::Leaf { data, positions, .. } => {
+            QuadTree::Leaf { data, positions, sizes, .. } => {
                 data.clear();
                 positions.clear();
+                sizes.clear();
             }
-            QuadTree::Quads(quads) => {
+            QuadTree::Quads(quads, straddling) => {
+                straddling.clear();
                 for quad in quads.iter_mut() {
                     quad.clear();
                 }
             }
         }
     }
-    /// Insert a point into the correct leaf, or return false if it doesn't fit
-    fn can_insert(&mut self, x: u16, y: u16) -> bool {
+    /// Empty the tree and give it a new item type, keeping its shape and the capacity of its buckets.
+    /// This lets a tree of references into a model be filled again for the next borrow of the model.
+    pub fn recycle<OtherT>(self) -> QuadTree<OtherT, CoordT> {
+        match self {
+            QuadTree::Leaf { data, mut positions, mut sizes, rect_x, rect_y, rect_w, rect_h, split } => {
+                positions.clear();
+                sizes.clear();
+                QuadTree::Leaf { data: recycle_vec(data), positions, sizes, rect_x, rect_y, rect_w, rect_h, split }
+            }
+            QuadTree::Quads(quads, straddling) => {
+                QuadTree::Quads(quads.map(|quad| Box::new(quad.recycle())), straddling.recycle())
+            }
+        }
+    }
+    /// Reserve room for additional items in every leaf
+    fn reserve_leaves(&mut self, additional: usize) {
         match self {
-            QuadTree::Leaf { rect_x, rect_y, rect_w, rect_h, .. } => {
-                x >= *rect_x && x < *rect_x + *rect_w && y >= *rect_y && y < *rect_y + *rect_h
+            QuadTree::Leaf { data, positions, sizes, .. } => {
+                data.reserve(additional);
+                positions.reserve(additional);
+                sizes.reserve(additional);
             }
-            QuadTree::Quads(quads) => {
+            QuadTree::Quads(quads, _) => {
                 for quad in quads.iter_mut() {
-                    //short circuit if we find a leaf that accepts the point
-                    if quad.can_insert(x, y) {
-                        return true;
-                    }
+                    quad.reserve_leaves(additional);
                 }
-                false
             }
         }
     }
     // Insert a point into the correct leaf, or return false if it doesn't fit
-    pub fn insert(&mut self, x: u16, y: u16, data: DataT) -> bool {
+    pub fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.try_insert(x, y, data).is_ok()
+    }
+    /// Insert a point into the correct leaf. If it doesn't fit, the data is given back with the error, so
+    /// the caller can keep it or store it elsewhere.
+    pub fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        let bounds = self.bounds();
+        if let Err(error) = check_bounds(bounds, x, y) {
+            return Err((data, error));
+        }
+        self.insert_sized(bounds, (x, y, CoordT::ZERO, CoordT::ZERO), data);
+        Ok(())
+    }
+    /// Insert an item with a bounding box. The item is stored once: in the leaf that fully contains the box,
+    /// or in the straddling bucket of the smallest quadrant that does. Returns false if the top left corner
+    /// is outside the tree. A box that hangs over the edge of the tree is kept at the root.
+    pub fn insert_box(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT, data: DataT) -> bool {
+        self.try_insert_box(x, y, w, h, data).is_ok()
+    }
+    /// Insert an item with a bounding box like insert_box. If the top left corner is outside the tree, the data
+    /// is given back with the error.
+    pub fn try_insert_box(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        let bounds = self.bounds();
+        if let Err(error) = check_bounds(bounds, x, y) {
+            return Err((data, error));
+        }
+        self.insert_sized(bounds, (x, y, w, h), data);
+        Ok(())
+    }
+    /// Insert an item whose top left corner is inside node_rect, descending while the box fits in a single quadrant
+    fn insert_sized(&mut self, node_rect: Rect<CoordT>, item: Rect<CoordT>, data: DataT) {
         match self {
-            QuadTree::Leaf { rect_x, rect_y, rect_w, rect_h, data: leaf_data, positions: leaf_positions, .. } => {
-                if x >= *rect_x && x < *rect_x + *rect_w && y >= *rect_y && y < *rect_y + *rect_h {
-                    leaf_data.push(data);
-                    leaf_positions.push((x, y));
-                    true
+            QuadTree::Leaf { data: leaf_data, positions, sizes, .. } => {
+                leaf_data.push(data);
+                positions.push((item.0, item.1));
+                sizes.push((item.2, item.3));
+                self.split_if_full();
+            }
+            QuadTree::Quads(quads, straddling) => {
+                let index = quadrant_of(node_rect, item.0, item.1);
+                let quad_rect = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[index];
+                if rect::fits(item, quad_rect) {
+                    quads[index].insert_sized(quad_rect, item, data);
                 } else {
-                    false
+                    straddling.push(item, data);
+                }
+            }
+        }
+    }
+    /// Split an adaptive leaf once it holds more items than its capacity
+    fn split_if_full(&mut self) {
+        if let QuadTree::Leaf { data, rect_w, rect_h, split: Some(rule), .. } = self {
+            //a 1 unit wide leaf can not be divided any further
+            if data.len() > rule.capacity && rule.depth_left > 0 && rect_w.half() > CoordT::ZERO && rect_h.half() > CoordT::ZERO {
+                self.split();
+            }
+        }
+    }
+    /// Turn an adaptive leaf into 4 adaptive leaves, and move its items into them
+    fn split(&mut self) {
+        if let QuadTree::Leaf { data, positions, sizes, rect_x, rect_y, rect_w, rect_h, split: Some(rule) } = self {
+            let node_rect = (*rect_x, *rect_y, *rect_w, *rect_h);
+            let rect = divide_into_4(*rect_x, *rect_y, *rect_w, *rect_h);
+            let (capacity, depth_left) = (rule.capacity, rule.depth_left - 1);
+            let data = std::mem::take(data);
+            let positions = std::mem::take(positions);
+            let sizes = std::mem::take(sizes);
+            *self = QuadTree::Quads([
+                Box::new(QuadTree::new_adaptive(rect[0].0, rect[0].1, rect[0].2, rect[0].3, capacity, depth_left)),
+                Box::new(QuadTree::new_adaptive(rect[1].0, rect[1].1, rect[1].2, rect[1].3, capacity, depth_left)),
+                Box::new(QuadTree::new_adaptive(rect[2].0, rect[2].1, rect[2].2, rect[2].3, capacity, depth_left)),
+                Box::new(QuadTree::new_adaptive(rect[3].0, rect[3].1, rect[3].2, rect[3].3, capacity, depth_left)),
+            ], Straddling::new());
+            for ((data, (x, y)), (w, h)) in data.into_iter().zip(positions).zip(sizes) {
+                self.insert_sized(node_rect, (x, y, w, h), data);
+            }
+        }
+    }
+    /// Merge 4 sibling adaptive leaves back into one leaf when their combined count no longer exceeds
+    /// the bucket capacity. This is applied bottom up, so whole subtrees can collapse at once.
+    /// Fixed depth trees built with new_empty are never merged.
+    pub fn merge_sparse(&mut self) {
+        let QuadTree::Quads(quads, _) = self else { return };
+        for quad in quads.iter_mut() {
+            quad.merge_sparse();
+        }
+        self.merge_children();
+    }
+    /// Merge sparse adaptive leaves on the path from this node down to the leaf that contains the point,
+    /// bottom up. A removal at the point can only have made the nodes on this path sparse.
+    fn merge_around(&mut self, node_rect: Rect<CoordT>, x: CoordT, y: CoordT) {
+        let QuadTree::Quads(quads, _) = self else { return };
+        let index = quadrant_of(node_rect, x, y);
+        quads[index].merge_around(divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[index], x, y);
+        self.merge_children();
+    }
+    /// Merge the 4 children of this node into one leaf if they are all adaptive leaves, and together with the
+    /// straddling bucket hold no more items than the capacity
+    fn merge_children(&mut self) {
+        let QuadTree::Quads(quads, straddling) = self else { return };
+        let mut count = straddling.len();
+        let mut rule = None;
+        for quad in quads.iter() {
+            match quad.as_ref() {
+                QuadTree::Leaf { data, split: Some(split), .. } => {
+                    count += data.len();
+                    rule = Some(*split);
+                }
+                _ => return,
+            }
+        }
+        let Some(rule) = rule else { return };
+        if count > rule.capacity {
+            return;
+        }
+        let mut merged_data = Vec::with_capacity(count);
+        let mut merged_positions = Vec::with_capacity(count);
+        let mut merged_sizes = Vec::with_capacity(count);
+        merged_data.append(&mut straddling.data);
+        merged_positions.append(&mut straddling.positions);
+        merged_sizes.append(&mut straddling.sizes);
+        let (rect_x, rect_y, rect_w, rect_h) = self.bounds();
+        let QuadTree::Quads(quads, _) = self else { return };
+        for quad in quads.iter_mut() {
+            if let QuadTree::Leaf { data, positions, sizes, .. } = quad.as_mut() {
+                merged_data.append(data);
+                merged_positions.append(positions);
+                merged_sizes.append(sizes);
+            }
+        }
+        *self = QuadTree::Leaf {
+            data: merged_data,
+            positions: merged_positions,
+            sizes: merged_sizes,
+            rect_x,
+            rect_y,
+            rect_w,
+            rect_h,
+            split: Some(SplitRule { capacity: rule.capacity, depth_left: rule.depth_left + 1 }),
+        };
+    }
+    /// Return a reference to the leaf that contains the point, descending from the root in O(depth)
+    fn get_leaf_around(&self, x: CoordT, y: CoordT) -> Result<&QuadTree<DataT, CoordT>, QuadTreeError<CoordT>> {
+        let bounds = self.bounds();
+        check_bounds(bounds, x, y)?;
+        Ok(self.leaf_around(bounds, x, y))
+    }
+    /// Return a mutable reference to the leaf that contains the point, descending from the root in O(depth)
+    fn get_mut_leaf_around(&mut self, x: CoordT, y: CoordT) -> Result<&mut QuadTree<DataT, CoordT>, QuadTreeError<CoordT>> {
+        let bounds = self.bounds();
+        check_bounds(bounds, x, y)?;
+        Ok(self.leaf_around_mut(bounds, x, y))
+    }
+    /// The leaf below this node that contains a point of node_rect
+    fn leaf_around(&self, node_rect: Rect<CoordT>, x: CoordT, y: CoordT) -> &QuadTree<DataT, CoordT> {
+        match self {
+            QuadTree::Quads(quads, _) => {
+                let index = quadrant_of(node_rect, x, y);
+                quads[index].leaf_around(divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[index], x, y)
+            }
+            leaf => leaf,
+        }
+    }
+    /// Mutable version of leaf_around
+    fn leaf_around_mut(&mut self, node_rect: Rect<CoordT>, x: CoordT, y: CoordT) -> &mut QuadTree<DataT, CoordT> {
+        match self {
+            QuadTree::Quads(quads, _) => {
+                let index = quadrant_of(node_rect, x, y);
+                quads[index].leaf_around_mut(divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[index], x, y)
+            }
+            leaf => leaf,
+        }
+    }
+    /// Remove the items stored at exactly (x, y) for which the predicate returns true, and return them.
+    /// Only the leaf that owns the point, and the straddling buckets above it, are searched.
+    /// Adaptive leaves are merged afterwards if they became sparse.
+    pub fn remove(&mut self, x: CoordT, y: CoordT, mut predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        let mut removed = Vec::new();
+        let bounds = self.bounds();
+        if !rect::contains(bounds, x, y) {
+            return Vec::new();
+        }
+        self.for_each_bucket_around_mut(bounds, x, y, &mut |data, positions, sizes| {
+            removed.append(&mut take_where(data, positions, sizes, &mut |data, data_x, data_y| data_x == x && data_y == y && predicate(data)));
+        });
+        if !removed.is_empty() {
+            self.merge_around(bounds, x, y);
+        }
+        removed
+    }
+    /// Remove every item in the tree for which the predicate returns true, and return them.
+    /// The predicate is given the item and its stored position. Adaptive leaves are merged in the same pass if they became sparse.
+    pub fn remove_where(&mut self, mut predicate: impl FnMut(&DataT, CoordT, CoordT) -> bool) -> Vec<DataT> {
+        let mut removed = Vec::new();
+        self.take_where_below(&mut predicate, &mut removed);
+        removed
+    }
+    /// Take the items below this node for which the predicate returns true, merging the nodes that lost items
+    /// on the way back up
+    fn take_where_below(&mut self, predicate: &mut impl FnMut(&DataT, CoordT, CoordT) -> bool, removed: &mut Vec<DataT>) {
+        let before = removed.len();
+        match self {
+            QuadTree::Leaf { data, positions, sizes, .. } => removed.append(&mut take_where(data, positions, sizes, predicate)),
+            QuadTree::Quads(quads, straddling) => {
+                removed.append(&mut straddling.remove_where(predicate));
+                for quad in quads.iter_mut() {
+                    quad.take_where_below(predicate, removed);
                 }
             }
-            QuadTree::Quads(quads) => {
-                //unroll the top level for loop to show borrow checker insert is only called once
-                if quads[0].can_insert(x, y) {
-                    quads[0].insert(x, y, data);
-                    return true;
+        }
+        if removed.len() > before {
+            self.merge_children();
+        }
+    }
+    /// Move the item stored at (old_x, old_y) for which the matcher returns true to (new_x, new_y).
+    /// The position is updated in place when the item stays in its leaf, otherwise the item is taken out and
+    /// inserted again with its size. Returns false, leaving the tree unchanged, if there is no such item or the
+    /// new position is outside the tree. Adaptive leaves are not merged here, call merge_sparse after a batch of moves.
+    pub fn relocate(&mut self, old_x: CoordT, old_y: CoordT, new_x: CoordT, new_y: CoordT, mut matcher: impl FnMut(&DataT) -> bool) -> bool {
+        let bounds = self.bounds();
+        if !rect::contains(bounds, new_x, new_y) {
+            return false;
+        }
+        match self.move_or_take(bounds, (old_x, old_y), (new_x, new_y), &mut matcher) {
+            Relocated::NotFound => false,
+            Relocated::InPlace => true,
+            Relocated::Taken(data, (w, h)) => {
+                self.insert_sized(bounds, (new_x, new_y, w, h), data);
+                true
+            }
+        }
+    }
+    /// Find the item at old below this node, and move it in place if its box still fits in its leaf at new.
+    /// Otherwise take it out. Only the leaf that owns old and the straddling buckets above it are searched.
+    fn move_or_take(&mut self, node_rect: Rect<CoordT>, old: (CoordT, CoordT), new: (CoordT, CoordT), matcher: &mut impl FnMut(&DataT) -> bool) -> Relocated<DataT, CoordT> {
+        match self {
+            QuadTree::Leaf { data, positions, sizes, .. } => {
+                let Some(index) = find_at(data, positions, old.0, old.1, matcher) else { return Relocated::NotFound };
+                let (w, h) = sizes[index];
+                if rect::contains(node_rect, new.0, new.1) && rect::fits((new.0, new.1, w, h), node_rect) {
+                    positions[index] = new;
+                    Relocated::InPlace
+                } else {
+                    let (data, size) = take_at(data, positions, sizes, index);
+                    Relocated::Taken(data, size)
                 }
-                else if quads[1].can_insert(x, y) {
-                    quads[1].insert(x, y, data);
-                    return true;
+            }
+            QuadTree::Quads(quads, straddling) => {
+                if let Some(index) = find_at(&straddling.data, &straddling.positions, old.0, old.1, matcher) {
+                    let (data, size) = take_at(&mut straddling.data, &mut straddling.positions, &mut straddling.sizes, index);
+                    return Relocated::Taken(data, size);
                 }
-                else if quads[2].can_insert(x, y) {
-                    quads[2].insert(x, y, data);
-                    return true;
+                if !rect::contains(node_rect, old.0, old.1) {
+                    return Relocated::NotFound;
                 }
-                else if quads[3].can_insert(x, y) {
-                    quads[3].insert(x, y, data);
-                    return true;
+                let index = quadrant_of(node_rect, old.0, old.1);
+                quads[index].move_or_take(divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[index], old, new, matcher)
+            }
+        }
+    }
+    /// Call f on the leaf that contains the point and on the straddling bucket of every node above it
+    fn for_each_bucket_around_mut(&mut self, node_rect: Rect<CoordT>, x: CoordT, y: CoordT, f: &mut impl FnMut(&mut Vec<DataT>, &mut Vec<(CoordT, CoordT)>, &mut Vec<(CoordT, CoordT)>)) {
+        match self {
+            QuadTree::Leaf { data, positions, sizes, .. } => f(data, positions, sizes),
+            QuadTree::Quads(quads, straddling) => {
+                f(&mut straddling.data, &mut straddling.positions, &mut straddling.sizes);
+                let index = quadrant_of(node_rect, x, y);
+                quads[index].for_each_bucket_around_mut(divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[index], x, y, f);
+            }
+        }
+    }
+    /// The bounding box of this node, computed from its top left and bottom right leaves
+    fn bounds(&self) -> Rect<CoordT> {
+        let (rect_x, rect_y, _, _) = self.corner_leaf(0);
+        let (far_x, far_y, far_w, far_h) = self.corner_leaf(3);
+        (rect_x, rect_y, far_x - rect_x + far_w, far_y - rect_y + far_h)
+    }
+    /// The bounds of the leaf reached by always taking the quadrant at index, which is a corner of the node
+    fn corner_leaf(&self, index: usize) -> Rect<CoordT> {
+        match self {
+            QuadTree::Leaf { rect_x, rect_y, rect_w, rect_h, .. } => (*rect_x, *rect_y, *rect_w, *rect_h),
+            QuadTree::Quads(quads, _) => quads[index].corner_leaf(index),
+        }
+    }
+    /// Call f with the data, positions and sizes of every leaf whose bounds pass the touches test, and of the
+    /// straddling buckets above them. node_rect is the bounds of this node, which lets whole quadrants be
+    /// skipped without visiting their leaves.
+    fn for_each_bucket_touching<'a>(&'a self, node_rect: Rect<CoordT>, touches: &impl Fn(Rect<CoordT>) -> bool, f: &mut impl FnMut(&'a [DataT], &'a [(CoordT, CoordT)], &'a [(CoordT, CoordT)])) {
+        if !touches(node_rect) {
+            return;
+        }
+        match self {
+            QuadTree::Leaf { data, positions, sizes, .. } => f(data, positions, sizes),
+            QuadTree::Quads(quads, straddling) => {
+                f(&straddling.data, &straddling.positions, &straddling.sizes);
+                let rects = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3);
+                for (quad, quad_rect) in quads.iter().zip(rects) {
+                    quad.for_each_bucket_touching(quad_rect, touches, f);
                 }
-                false
             }
         }
     }
-    /// Return a reference to the leaf that contains the point
-    fn get_leaf_around(&self, x: u16, y: u16) -> Option<&QuadTree<DataT>> {
+    /// Mutable version of for_each_bucket_touching
+    fn for_each_bucket_touching_mut<'a>(&'a mut self, node_rect: Rect<CoordT>, touches: &impl Fn(Rect<CoordT>) -> bool, f: &mut impl FnMut(&'a mut [DataT], &'a [(CoordT, CoordT)], &'a [(CoordT, CoordT)])) {
+        if !touches(node_rect) {
+            return;
+        }
         match self {
-            QuadTree::Leaf { rect_x, rect_y, rect_w, rect_h, .. } => {
-                if x >= *rect_x && x < *rect_x + *rect_w && y >= *rect_y && y < *rect_y + *rect_h {
-                    Some(self)
-                } else {
-                    None
+            QuadTree::Leaf { data, positions, sizes, .. } => f(data, positions, sizes),
+            QuadTree::Quads(quads, straddling) => {
+                f(&mut straddling.data, &straddling.positions, &straddling.sizes);
+                let rects = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3);
+                for (quad, quad_rect) in quads.iter_mut().zip(rects) {
+                    quad.for_each_bucket_touching_mut(quad_rect, touches, f);
+                }
+            }
+        }
+    }
+    /// Return every item whose stored position is inside the rect. Only the leaves that overlap the rect are visited.
+    pub fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(self.bounds(), &|node| rect::overlaps(node, query), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                if rect::contains(query, item_x, item_y) {
+                    found.push(item);
                 }
             }
-            QuadTree::Quads(quads) => {
-                for quad in quads.iter() {
-                    //short circuit if we find a leaf that accepts the point
-                    if let Some(leaf) = quad.get_leaf_around(x, y) {
-                        return Some(leaf);
+        });
+        found
+    }
+    /// Return a mutable reference to every item whose stored position is inside the rect
+    pub fn query_rect_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        let bounds = self.bounds();
+        self.for_each_bucket_touching_mut(bounds, &|node| rect::overlaps(node, query), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter_mut().zip(positions) {
+                if rect::contains(query, item_x, item_y) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return every item whose stored position is within radius of the center. Quadrants that do not
+    /// intersect the circle are skipped.
+    pub fn query_radius(&self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(self.bounds(), &|node| rect::overlaps_circle(node, center_x, center_y, radius), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                if rect::distance_squared(center_x, center_y, item_x, item_y) <= max_d2 {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return a mutable reference to every item whose stored position is within radius of the center
+    pub fn query_radius_mut(&mut self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&mut DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let mut found = Vec::new();
+        let bounds = self.bounds();
+        self.for_each_bucket_touching_mut(bounds, &|node| rect::overlaps_circle(node, center_x, center_y, radius), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter_mut().zip(positions) {
+                if rect::distance_squared(center_x, center_y, item_x, item_y) <= max_d2 {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Like query_rect, on a world that wraps around at the edges of the tree, so a rect that runs past the far
+    /// edge goes on from the near one. The rect can start outside the tree, it is wrapped into it first.
+    pub fn query_rect_wrapped(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let bounds = self.bounds();
+        let pieces = rect::wrap_pieces((x, y, w, h), bounds);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(bounds, &|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                if pieces.iter().any(|&piece| rect::contains(piece, item_x, item_y)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Mutable version of query_rect_wrapped
+    pub fn query_rect_wrapped_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let bounds = self.bounds();
+        let pieces = rect::wrap_pieces((x, y, w, h), bounds);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching_mut(bounds, &|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter_mut().zip(positions) {
+                if pieces.iter().any(|&piece| rect::contains(piece, item_x, item_y)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Like query_radius, on a world that wraps around at the edges of the tree. Distances are measured the
+    /// short way around, and the leaves are picked by the square around the circle.
+    pub fn query_radius_wrapped(&self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let bounds = self.bounds();
+        let (center_x, center_y, pieces) = rect::wrap_circle(bounds, center_x, center_y, radius);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(bounds, &|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                if rect::distance_squared_wrapped(bounds, center_x, center_y, item_x, item_y) <= max_d2 {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Mutable version of query_radius_wrapped
+    pub fn query_radius_wrapped_mut(&mut self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&mut DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let bounds = self.bounds();
+        let (center_x, center_y, pieces) = rect::wrap_circle(bounds, center_x, center_y, radius);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching_mut(bounds, &|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter_mut().zip(positions) {
+                if rect::distance_squared_wrapped(bounds, center_x, center_y, item_x, item_y) <= max_d2 {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return the closest item to the point and its squared distance, or None if the tree is empty
+    pub fn nearest(&self, x: CoordT, y: CoordT) -> Option<(f64, &DataT)> {
+        self.k_nearest(x, y, 1).into_iter().next()
+    }
+    /// Return up to k items closest to the point with their squared distances, closest first.
+    /// Quadrants are visited best first, ordered by the distance to their rects, and the search stops
+    /// once the next quadrant is further away than the k-th item found so far.
+    pub fn k_nearest(&self, x: CoordT, y: CoordT, k: usize) -> Vec<(f64, &DataT)> {
+        let mut best = KNearest::new(k);
+        //the heap holds indices into nodes, because the nodes themselves can not be ordered
+        let mut nodes = Vec::new();
+        let mut queue = BinaryHeap::new();
+        let bounds = self.bounds();
+        if let Some(d2) = rect::distance_squared_to(bounds, x, y) {
+            nodes.push((self, bounds));
+            queue.push(Closest(d2, 0));
+        }
+        while let Some(Closest(d2, index)) = queue.pop() {
+            if !best.wants(d2) {
+                break;
+            }
+            let (node, node_rect) = nodes[index];
+            match node {
+                QuadTree::Leaf { data, positions, .. } => {
+                    for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                        best.offer(rect::distance_squared(x, y, item_x, item_y), item);
+                    }
+                }
+                QuadTree::Quads(quads, straddling) => {
+                    for (item, &(item_x, item_y)) in straddling.data.iter().zip(&straddling.positions) {
+                        best.offer(rect::distance_squared(x, y, item_x, item_y), item);
+                    }
+                    let rects = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3);
+                    for (quad, quad_rect) in quads.iter().zip(rects) {
+                        if let Some(d2) = rect::distance_squared_to(quad_rect, x, y) {
+                            nodes.push((quad.as_ref(), quad_rect));
+                            queue.push(Closest(d2, nodes.len() - 1));
+                        }
                     }
                 }
-                None
             }
         }
+        best.into_vec()
     }
-    /// Return a mutable reference to the leaf that contains the point
-    fn get_mut_leaf_around(&mut self, x: u16, y: u16) -> Option<&mut QuadTree<DataT>> {
+    /// Return every item whose box overlaps the query box. Items inserted as a point have a size of 0.
+    /// Every item is stored once, so no item is returned twice.
+    pub fn query_box(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        //boxes hanging over the edge of the tree are kept at the root, so it is always visited
+        let bounds = self.bounds();
+        self.for_each_bucket_touching(bounds, &|node| node == bounds || rect::overlaps(node, query), &mut |data, positions, sizes| {
+            for ((item, &(item_x, item_y)), &(item_w, item_h)) in data.iter().zip(positions).zip(sizes) {
+                if rect::overlaps(query, (item_x, item_y, item_w, item_h)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Mutable version of query_box
+    pub fn query_box_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        //boxes hanging over the edge of the tree are kept at the root, so it is always visited
+        let bounds = self.bounds();
+        self.for_each_bucket_touching_mut(bounds, &|node| node == bounds || rect::overlaps(node, query), &mut |data, positions, sizes| {
+            for ((item, &(item_x, item_y)), &(item_w, item_h)) in data.iter_mut().zip(positions).zip(sizes) {
+                if rect::overlaps(query, (item_x, item_y, item_w, item_h)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return every item of every leaf that the box touches, and the straddling items above them. Unlike broad_phase,
+    /// this includes the neighbouring leaves when the box straddles a leaf border. Each leaf is visited once, so no
+    /// item is returned twice.
+    pub fn broad_phase_neighbourhood(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(self.bounds(), &|node| rect::overlaps(node, query), &mut |data, _, _| found.extend(data));
+        found
+    }
+    /// Mutable version of broad_phase_neighbourhood
+    pub fn broad_phase_neighbourhood_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        let bounds = self.bounds();
+        self.for_each_bucket_touching_mut(bounds, &|node| rect::overlaps(node, query), &mut |data, _, _| found.extend(data));
+        found
+    }
+    /// Like broad_phase_neighbourhood, on a world that wraps around at the edges of the tree, so the leaves on the
+    /// far side of an edge are included, see neighbourhood_wrapped. Each leaf is still visited once.
+    pub fn broad_phase_neighbourhood_wrapped(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let bounds = self.bounds();
+        let pieces = rect::wrap_pieces((x, y, w, h), bounds);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(bounds, &|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, _, _| found.extend(data));
+        found
+    }
+    /// Mutable version of broad_phase_neighbourhood_wrapped
+    pub fn broad_phase_neighbourhood_wrapped_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let bounds = self.bounds();
+        let pieces = rect::wrap_pieces((x, y, w, h), bounds);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching_mut(bounds, &|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, _, _| found.extend(data));
+        found
+    }
+    /// Call f on every unordered pair of candidates once: every pair of items in the same leaf, like broad_phase,
+    /// and every pair of a straddling item with an item below or beside it whose box overlaps it, the straddling
+    /// item first. Items inserted with insert_box, like the entities of with_tree_boxes, have their whole box in
+    /// their node, so this covers every overlapping pair. Items inserted as a point have no box, so for them only
+    /// pairs in the same leaf are found.
+    pub fn for_each_pair_mut(&mut self, mut f: impl FnMut(&mut DataT, &mut DataT)) {
+        let bounds = self.bounds();
+        self.for_each_pair_below_mut(bounds, &mut f);
+    }
+    fn for_each_pair_below_mut(&mut self, node_rect: Rect<CoordT>, f: &mut impl FnMut(&mut DataT, &mut DataT)) {
         match self {
-            QuadTree::Leaf { rect_x, rect_y, rect_w, rect_h, .. } => {
-                if x >= *rect_x && x < *rect_x + *rect_w && y >= *rect_y && y < *rect_y + *rect_h {
-                    Some(self)
-                } else {
-                    None
+            QuadTree::Leaf { data, .. } => for_each_pair_in(data, f),
+            QuadTree::Quads(quads, straddling) => {
+                let rects = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3);
+                for i in 0..straddling.data.len() {
+                    let (x, y) = straddling.positions[i];
+                    let (w, h) = straddling.sizes[i];
+                    let item = (x, y, w, h);
+                    let (data, later) = straddling.data.split_at_mut(i + 1);
+                    let this = &mut data[i];
+                    for ((other, &(other_x, other_y)), &(other_w, other_h)) in later.iter_mut().zip(&straddling.positions[i + 1..]).zip(&straddling.sizes[i + 1..]) {
+                        if rect::overlaps(item, (other_x, other_y, other_w, other_h)) {
+                            f(this, other);
+                        }
+                    }
+                    for (quad, quad_rect) in quads.iter_mut().zip(rects) {
+                        quad.for_each_bucket_touching_mut(quad_rect, &|node| rect::overlaps(node, item), &mut |data, positions, sizes| {
+                            for ((other, &(other_x, other_y)), &(other_w, other_h)) in data.iter_mut().zip(positions).zip(sizes) {
+                                if rect::overlaps(item, (other_x, other_y, other_w, other_h)) {
+                                    f(this, other);
+                                }
+                            }
+                        });
+                    }
+                }
+                for (quad, quad_rect) in quads.iter_mut().zip(rects) {
+                    quad.for_each_pair_below_mut(quad_rect, f);
                 }
             }
-            QuadTree::Quads(quads) => {
-                for quad in quads.iter_mut() {
-                    //short circuit if we find a leaf that accepts the point
-                    if let Some(leaf) = quad.get_mut_leaf_around(x, y) {
-                        return Some(leaf);
+        }
+    }
+    /// Return the pairs of for_each_pair_mut
+    pub fn candidate_pairs(&self) -> Vec<(&DataT, &DataT)> {
+        let mut pairs = Vec::new();
+        self.pairs_below(self.bounds(), &mut pairs);
+        pairs
+    }
+    fn pairs_below<'a>(&'a self, node_rect: Rect<CoordT>, pairs: &mut Vec<(&'a DataT, &'a DataT)>) {
+        match self {
+            QuadTree::Leaf { data, .. } => pairs_in(data, pairs),
+            QuadTree::Quads(quads, straddling) => {
+                let rects = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3);
+                for (i, (this, (&(x, y), &(w, h)))) in straddling.data.iter().zip(straddling.positions.iter().zip(&straddling.sizes)).enumerate() {
+                    let item = (x, y, w, h);
+                    for ((other, &(other_x, other_y)), &(other_w, other_h)) in straddling.data[i + 1..].iter().zip(&straddling.positions[i + 1..]).zip(&straddling.sizes[i + 1..]) {
+                        if rect::overlaps(item, (other_x, other_y, other_w, other_h)) {
+                            pairs.push((this, other));
+                        }
+                    }
+                    for (quad, quad_rect) in quads.iter().zip(rects) {
+                        quad.for_each_bucket_touching(quad_rect, &|node| rect::overlaps(node, item), &mut |data, positions, sizes| {
+                            for ((other, &(other_x, other_y)), &(other_w, other_h)) in data.iter().zip(positions).zip(sizes) {
+                                if rect::overlaps(item, (other_x, other_y, other_w, other_h)) {
+                                    pairs.push((this, other));
+                                }
+                            }
+                        });
                     }
                 }
-                None
+                for (quad, quad_rect) in quads.iter().zip(rects) {
+                    quad.pairs_below(quad_rect, pairs);
+                }
             }
         }
     }
-    /// Convienience function for get_leaf_around that returns a reference to the vec of data
-    pub fn broad_phase(&self, x: u16, y: u16) -> &Vec<DataT> {
-        match self.get_leaf_around(x, y).unwrap() {
-            QuadTree::Leaf { data, .. } => data,
+    /// Convienience function for get_leaf_around that returns a reference to the vec of data. Only the items
+    /// stored in the leaf are in it, so boxes that insert_box kept in a straddling bucket above the leaf are
+    /// missing, use broad_phase_neighbourhood or query_box to find those.
+    /// Panics if the point is outside the tree, see try_broad_phase.
+    pub fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.try_broad_phase(x, y).unwrap()
+    }
+    /// Convienience function for get_mut_leaf_around that returns a mutable reference to the vec of data.
+    /// Panics if the point is outside the tree, see try_broad_phase_mut.
+    pub fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.try_broad_phase_mut(x, y).unwrap()
+    }
+    /// Return a reference to the vec of data of the leaf that contains the point, or OutOfBounds. Like
+    /// broad_phase, this leaves out the straddling boxes.
+    pub fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        match self.get_leaf_around(x, y)? {
+            QuadTree::Leaf { data, .. } => Ok(data),
             _ => unreachable!("get_leaf_around returned a non-leaf"),
         }
     }
-    /// Convienience function for get_mut_leaf_around that returns a mutable reference to the vec of data
-    pub fn broad_phase_mut(&mut self, x: u16, y: u16) -> &mut Vec<DataT> {
-        match self.get_mut_leaf_around(x, y).unwrap() {
-            QuadTree::Leaf { data, .. } => data,
+    /// Mutable version of try_broad_phase
+    pub fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        match self.get_mut_leaf_around(x, y)? {
+            QuadTree::Leaf { data, .. } => Ok(data),
             _ => unreachable!("get_leaf_around returned a non-leaf"),
         }
     }
 }
 
-use super::GetX;
-use super::GetY;
+impl<DataT, CoordT: Coord> FromBuilder<DataT, CoordT> for QuadTree<DataT, CoordT> {
+    fn from_builder(builder: &QuadTreeBuilder<CoordT>) -> Self {
+        let mut tree = QuadTree::new_empty(builder.rect_x, builder.rect_y, builder.rect_w, builder.rect_h, builder.levels());
+        tree.reserve_leaves(builder.leaf_capacity);
+        tree
+    }
+    fn insert_point(&mut self, x: CoordT, y: CoordT, data: DataT) {
+        self.insert(x, y, data);
+    }
+}
+
+impl<DataT, CoordT: Coord> SpatialIndex<DataT, CoordT> for QuadTree<DataT, CoordT> {
+    fn clear(&mut self) {
+        self.clear();
+    }
+    fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.insert(x, y, data)
+    }
+    fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        self.try_insert(x, y, data)
+    }
+    fn remove(&mut self, x: CoordT, y: CoordT, predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        self.remove(x, y, predicate)
+    }
+    fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.broad_phase(x, y)
+    }
+    fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.broad_phase_mut(x, y)
+    }
+    fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase(x, y)
+    }
+    fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase_mut(x, y)
+    }
+    fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        self.query_rect(x, y, w, h)
+    }
+}
+
+impl<DataT, CoordT: Coord> SpatialQueries<DataT, CoordT> for QuadTree<DataT, CoordT> {
+    fn query_rect_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        self.query_rect_mut(x, y, w, h)
+    }
+    fn query_box(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        self.query_box(x, y, w, h)
+    }
+    fn query_box_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        self.query_box_mut(x, y, w, h)
+    }
+    fn query_radius(&self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&DataT> {
+        self.query_radius(center_x, center_y, radius)
+    }
+    fn query_radius_mut(&mut self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&mut DataT> {
+        self.query_radius_mut(center_x, center_y, radius)
+    }
+    fn k_nearest(&self, x: CoordT, y: CoordT, k: usize) -> Vec<(f64, &DataT)> {
+        self.k_nearest(x, y, k)
+    }
+    fn broad_phase_neighbourhood(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        self.broad_phase_neighbourhood(x, y, w, h)
+    }
+    fn broad_phase_neighbourhood_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        self.broad_phase_neighbourhood_mut(x, y, w, h)
+    }
+    fn for_each_pair_mut(&mut self, f: impl FnMut(&mut DataT, &mut DataT)) {
+        self.for_each_pair_mut(f)
+    }
+}
+
+use super::{GetX, GetY, GetWidth, GetHeight};
 
-impl<Entity: GetX+GetY> QuadTree<Entity> {
+impl<Entity: GetX<CoordT>+GetY<CoordT>, CoordT: Coord> QuadTree<Entity, CoordT> {
 
     /// Rebuild a QuadTree from a model. This is used to update the tree after the model has changed.
     /// It clears the tree, then puts references to the entities back in it, however it does not
     /// change the capacities of the underlying Vecs.
-    pub fn rebuild_from_model(tree: &mut QuadTree<&mut Entity>, model: &mut Vec<Entity>) {
+    /// This aliases the model through raw pointers, see with_tree for a version tied to the borrow of the model.
+    /// Returns how many entities were left out because they are outside the tree.
+    ///
+    /// # Safety
+    /// The references put in the tree are not tied to the borrow of the model. Until the tree is cleared or
+    /// rebuilt, the model must not be moved, dropped or resized, and an entity must not be reached through the
+    /// tree while a reference to it taken from the model is alive, or the other way around.
+    pub unsafe fn rebuild_from_model(tree: &mut QuadTree<&mut Entity, CoordT>, model: &mut [Entity]) -> usize {
+        //// SAFETY: The caller keeps to the contract, which is the same for both.
+        unsafe {QuadTree::rebuild_from_model_with_policy(tree, model, OutOfBounds::Reject)}
+    }
+
+    /// Rebuild a QuadTree from a model like rebuild_from_model, applying the policy to the entities outside the
+    /// tree. Clamped and wrapped entities are stored at the position they were moved to. A tree has no overflow
+    /// bucket, so Overflow leaves them out like Reject, see Bounded for a tree that keeps them.
+    /// Returns how many entities the policy was applied to.
+    ///
+    /// # Safety
+    /// The references put in the tree are not tied to the borrow of the model. Until the tree is cleared or
+    /// rebuilt, the model must not be moved, dropped or resized, and an entity must not be reached through the
+    /// tree while a reference to it taken from the model is alive, or the other way around.
+    pub unsafe fn rebuild_from_model_with_policy(tree: &mut QuadTree<&mut Entity, CoordT>, model: &mut [Entity], policy: OutOfBounds) -> usize {
         tree.clear();
-        for i in 0..model.len() {
-            let entity = &mut model[i] as *mut Entity;
-            //// SAFETY: This is safe because the tree is cleared before being filled.
+        let bounds = tree.bounds();
+        let mut outside = 0;
+        for entity in model.iter_mut() {
+            let entity = entity as *mut Entity;
+            //// SAFETY: The tree is cleared before being filled, and the caller keeps the model in place.
             let entity = unsafe {&mut *entity};
-            //insert a reference to the entity into the tree
-            tree.insert(entity.get_x(), entity.get_y(), entity);
+            let (x, y) = (entity.get_x(), entity.get_y());
+            if !rect::contains(bounds, x, y) {
+                outside += 1;
+            }
+            //insert a reference to the entity into the tree, where the policy puts it
+            if let Some((x, y)) = policy.place(bounds, x, y) {
+                tree.insert(x, y, entity);
+            }
         }
+        outside
     }
 
 
-    /// Build a new QuadTree from scratch, and put references to the entities in it. The references are
-    /// anotated as static because this uses unsafe code to create them.
-    pub fn build_new_from_model(model: & mut Vec<Entity>, width: u16, height: u16, depth: u16) -> QuadTree<&'static mut Entity> {
-        let mut tree = QuadTree::new_empty(0, 0, width, height, depth);
-        for i in 0..model.len() {
-            let entity = &mut model[i] as *mut Entity;
-            //// SAFETY: This is safe because the tree is new before being filled.
-            let entity =  unsafe{&mut *entity};
-            //insert a reference to the entity into the tree
+    /// Build a new QuadTree of references to the entities. The tree borrows the model, so the model can not
+    /// be dropped or changed while the tree is alive.
+    pub fn build_from_model(model: &mut [Entity], rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT, depth: u16) -> QuadTree<&mut Entity, CoordT> {
+        QuadTree::refill_from_model(QuadTree::new_empty(rect_x, rect_y, rect_w, rect_h, depth), model)
+    }
+
+    /// Fill an old tree with references to the entities of a new borrow of the model. The old tree is recycled,
+    /// so its shape and the capacities of its buckets are kept like in rebuild_from_model.
+    pub fn refill_from_model<'a>(tree: QuadTree<&mut Entity, CoordT>, model: &'a mut [Entity]) -> QuadTree<&'a mut Entity, CoordT> {
+        let mut tree = tree.recycle();
+        for entity in model.iter_mut() {
             tree.insert(entity.get_x(), entity.get_y(), entity);
         }
         tree
     }
+
+    /// Fill the tree with references to the model, and call f with it. The tree is emptied again when f returns,
+    /// so it can be kept from frame to frame next to the model without borrowing it. If f panics, the tree is
+    /// left as a single empty leaf.
+    pub fn with_tree<'a, R>(tree: &mut QuadTree<&mut Entity, CoordT>, model: &'a mut [Entity], f: impl FnOnce(&mut QuadTree<&'a mut Entity, CoordT>) -> R) -> R {
+        let empty = QuadTree::new_empty(CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, 0);
+        let mut filled = QuadTree::refill_from_model(std::mem::replace(tree, empty), model);
+        let result = f(&mut filled);
+        *tree = filled.recycle();
+        result
+    }
+
+    /// Fill a tree with the indices of the entities of the model, at their positions. A tree of indices does
+    /// not borrow the model, so it can be kept next to it and updated with refresh_from_model as entities move.
+    /// Returns how many entities were left out because they are outside the tree.
+    pub fn index_model(tree: &mut QuadTree<usize, CoordT>, model: &[Entity]) -> usize {
+        tree.clear();
+        model.iter().enumerate().filter(|&(index, entity)| !tree.insert(entity.get_x(), entity.get_y(), index)).count()
+    }
+
+    /// Move the index of an entity in a tree of indices after the entity moved away from (old_x, old_y).
+    /// The new position is read from the model, and other entities at the same position are left alone.
+    /// Returns false like relocate.
+    pub fn update_position(tree: &mut QuadTree<usize, CoordT>, model: &[Entity], index: usize, old_x: CoordT, old_y: CoordT) -> bool {
+        let entity = &model[index];
+        tree.relocate(old_x, old_y, entity.get_x(), entity.get_y(), |&other| other == index)
+    }
+
+    /// Update a tree of indices filled with index_model after some of the entities moved, instead of refilling it.
+    /// dirty holds the index into the model and the old position of every entity that moved since the last
+    /// refresh, and only those entities are touched. Returns how many of them could not be moved, because they
+    /// were not found at their old position or moved outside the tree. Those keep their old place in the tree.
+    /// Like remove, only the adaptive leaves on the paths to the old positions are merged if they became sparse.
+    pub fn refresh_from_model(tree: &mut QuadTree<usize, CoordT>, model: &[Entity], dirty: &[(usize, CoordT, CoordT)]) -> usize {
+        let bounds = tree.bounds();
+        let mut failed = 0;
+        for &(index, old_x, old_y) in dirty {
+            if QuadTree::update_position(tree, model, index, old_x, old_y) {
+                tree.merge_around(bounds, old_x, old_y);
+            } else {
+                failed += 1;
+            }
+        }
+        failed
+    }
+}
+
+impl<Entity: GetX<CoordT>+GetY<CoordT>+GetWidth<CoordT>+GetHeight<CoordT>, CoordT: Coord> QuadTree<Entity, CoordT> {
+
+    /// Fill an old tree with references to the entities of a new borrow of the model, like refill_from_model, but
+    /// with the box of every entity. Entities whose box crosses a border go to the straddling bucket of the
+    /// smallest quadrant that holds it, so for_each_pair_mut finds every overlapping pair.
+    pub fn refill_boxes_from_model<'a>(tree: QuadTree<&mut Entity, CoordT>, model: &'a mut [Entity]) -> QuadTree<&'a mut Entity, CoordT> {
+        let mut tree = tree.recycle();
+        for entity in model.iter_mut() {
+            tree.insert_box(entity.get_x(), entity.get_y(), entity.get_width(), entity.get_height(), entity);
+        }
+        tree
+    }
+
+    /// Like with_tree, with the tree filled by refill_boxes_from_model
+    pub fn with_tree_boxes<'a, R>(tree: &mut QuadTree<&mut Entity, CoordT>, model: &'a mut [Entity], f: impl FnOnce(&mut QuadTree<&'a mut Entity, CoordT>) -> R) -> R {
+        let empty = QuadTree::new_empty(CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, 0);
+        let mut filled = QuadTree::refill_boxes_from_model(std::mem::replace(tree, empty), model);
+        let result = f(&mut filled);
+        *tree = filled.recycle();
+        result
+    }
 }
 
-impl<Entity: GetX+GetY+Clone> QuadTree<Entity> {
+impl<Entity: GetX<CoordT>+GetY<CoordT>+Clone, CoordT: Coord> QuadTree<Entity, CoordT> {
 
     /// A version that returns a QuadTree that owns clones of the entities
-    pub fn build_owned_from_model(model: &mut Vec<Entity>, depth: u16) -> QuadTree<Entity> {
-        let mut tree = QuadTree::new_empty(0, 0, 1000, 1000, depth);
-        for i in 0..model.len() {
-            let entity = model[i].clone();
+    pub fn build_owned_from_model(model: &mut [Entity], rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT, depth: u16) -> QuadTree<Entity, CoordT> {
+        let mut tree = QuadTree::new_empty(rect_x, rect_y, rect_w, rect_h, depth);
+        for entity in model.iter() {
+            let entity = entity.clone();
             //insert a reference to the entity into the tree
             tree.insert(entity.get_x(), entity.get_y(), entity);
         }
         tree
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::{init_model, Entity, ModelConfig};
+
+    /// How many levels of quadrants there are above the deepest leaf
+    fn depth<DataT>(tree: &QuadTree<DataT>) -> u16 {
+        match tree {
+            QuadTree::Leaf { .. } => 0,
+            QuadTree::Quads(quads, _) => 1 + quads.iter().map(|quad| depth(quad)).max().unwrap(),
+        }
+    }
+
+    /// How many more times the root may split, or None if it is not an adaptive leaf
+    fn depth_left<DataT>(tree: &QuadTree<DataT>) -> Option<u16> {
+        match tree {
+            QuadTree::Leaf { split, .. } => split.map(|rule| rule.depth_left),
+            QuadTree::Quads(..) => None,
+        }
+    }
+
+    #[test]
+    fn adaptive_leaves_split_up_to_the_max_depth() {
+        let mut tree = QuadTree::new_adaptive(0, 0, 128, 128, 4, 3);
+        for (i, (x, y)) in [(10, 10), (100, 10), (10, 100), (100, 100)].into_iter().enumerate() {
+            assert!(tree.insert(x, y, i));
+        }
+        assert_eq!(depth(&tree), 0);
+        assert!(tree.insert(64, 64, 4));
+        assert_eq!(depth(&tree), 1);
+        //many items on the same spot split the leaf that holds them until the max depth, and no further
+        for i in 0..10 {
+            assert!(tree.insert(5, 5, 10 + i));
+        }
+        assert_eq!(depth(&tree), 3);
+        assert_eq!(tree.broad_phase(5, 5).len(), 11);
+        assert_eq!(tree.query_rect(0, 0, 128, 128).len(), 15);
+        //a 1 unit wide leaf can not be divided, whatever the max depth
+        let mut small = QuadTree::new_adaptive(0, 0, 2, 2, 1, 10);
+        for i in 0..10 {
+            assert!(small.insert(0, 0, i));
+        }
+        assert_eq!(depth(&small), 1);
+    }
+
+    #[test]
+    fn adaptive_leaves_merge_after_removals() {
+        let mut tree = QuadTree::new_adaptive(0, 0, 128, 128, 4, 3);
+        for i in 0..10 {
+            assert!(tree.insert(5, 5, i));
+        }
+        for (i, (x, y)) in [(100, 10), (10, 100), (100, 100)].into_iter().enumerate() {
+            assert!(tree.insert(x, y, 10 + i));
+        }
+        assert_eq!(depth(&tree), 3);
+        //removing most of the items on the spot merges the path to it, but the root still holds too many
+        assert_eq!(tree.remove(5, 5, |&i| i < 8).len(), 8);
+        assert_eq!(depth(&tree), 1);
+        assert_eq!(tree.query_rect(0, 0, 128, 128).len(), 5);
+        assert_eq!(tree.remove(100, 100, |_| true).len(), 1);
+        assert_eq!(depth(&tree), 0);
+        //the merged leaf gets back the splits its children had left
+        assert_eq!(depth_left(&tree), Some(3));
+        let mut left: Vec<usize> = tree.query_rect(0, 0, 128, 128).into_iter().copied().collect();
+        left.sort();
+        assert_eq!(left, vec![8, 9, 10, 11]);
+        //remove_where merges in the same pass
+        for i in 0..10 {
+            assert!(tree.insert(70, 70, 20 + i));
+        }
+        assert_eq!(depth(&tree), 3);
+        assert_eq!(tree.remove_where(|&i, _, _| i >= 20).len(), 10);
+        assert_eq!(depth(&tree), 0);
+        assert_eq!(depth_left(&tree), Some(3));
+    }
+
+    #[test]
+    fn relocate_moves_items_within_and_across_leaves() {
+        let mut tree = QuadTree::new_empty(0, 0, 128, 128, 3);
+        assert!(tree.insert(1, 1, 0) && tree.insert(1, 1, 1));
+        //inside the same 16x16 leaf, the other item at the same position stays
+        assert!(tree.relocate(1, 1, 15, 15, |&i| i == 0));
+        assert_eq!(tree.query_rect(1, 1, 1, 1), vec![&1]);
+        assert_eq!(tree.query_rect(15, 15, 1, 1), vec![&0]);
+        assert_eq!(tree.broad_phase(1, 1).len(), 2);
+        //into another leaf
+        assert!(tree.relocate(15, 15, 16, 100, |&i| i == 0));
+        assert_eq!(tree.broad_phase(1, 1), &vec![1]);
+        assert_eq!(tree.broad_phase(16, 100), &vec![0]);
+        //outside the tree, or from a position where the item is not, nothing moves
+        assert!(!tree.relocate(16, 100, 128, 5, |&i| i == 0));
+        assert!(!tree.relocate(1, 1, 5, 5, |&i| i == 0));
+        assert_eq!(tree.query_rect(16, 100, 1, 1), vec![&0]);
+        assert_eq!(tree.query_rect(0, 0, 128, 128).len(), 2);
+    }
+
+    #[test]
+    fn refresh_from_model_follows_the_moved_entities() {
+        let mut model = init_model(ModelConfig { model_size: 200, ..Default::default() });
+        let mut tree = QuadTree::new_adaptive(0, 0, 128, 128, 8, 4);
+        assert_eq!(QuadTree::index_model(&mut tree, &model), 0);
+        let mut dirty = Vec::new();
+        for (i, entity) in model.iter_mut().enumerate().step_by(3) {
+            dirty.push((i, entity.x, entity.y));
+            //every 30th entity walks off the world and keeps its old place in the tree
+            (entity.x, entity.y) = if i % 30 == 0 { (entity.x + 128, entity.y) } else { ((entity.x + 37) % 128, (entity.y * 3) % 128) };
+        }
+        assert_eq!(QuadTree::refresh_from_model(&mut tree, &model, &dirty), 7);
+        for (i, entity) in model.iter().enumerate() {
+            let (x, y) = if i % 30 == 0 { (entity.x - 128, entity.y) } else { (entity.x, entity.y) };
+            assert!(tree.query_rect(x, y, 1, 1).contains(&&i), "{} at {:?}", i, (x, y));
+        }
+        assert_eq!(tree.query_rect(0, 0, 128, 128).len(), 200);
+
+        //the entities leave a crowded spot for the four quadrants, so the path they left is merged
+        let mut model: Vec<Entity> = (0..10).map(|_| Entity { x: 5, y: 5, width: 1, height: 1, collision: false }).collect();
+        let mut tree = QuadTree::new_adaptive(0, 0, 128, 128, 4, 3);
+        assert_eq!(QuadTree::index_model(&mut tree, &model), 0);
+        assert_eq!(depth(&tree), 3);
+        let mut dirty = Vec::new();
+        for (i, (x, y)) in [(100, 10), (100, 10), (10, 100), (10, 100), (100, 100), (100, 100), (70, 70), (70, 70)].into_iter().enumerate() {
+            dirty.push((i, 5, 5));
+            (model[i].x, model[i].y) = (x, y);
+        }
+        assert_eq!(QuadTree::refresh_from_model(&mut tree, &model, &dirty), 0);
+        assert_eq!(depth(&tree), 1);
+        assert_eq!(tree.broad_phase(5, 5).len(), 2);
+    }
+
+    /// The items of the straddling bucket of the root
+    fn straddling_at_root<DataT: Copy>(tree: &QuadTree<DataT>) -> Vec<DataT> {
+        match tree {
+            QuadTree::Quads(_, straddling) => straddling.data.clone(),
+            QuadTree::Leaf { .. } => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn boxes_across_a_quadrant_edge_are_stored_once_and_removed() {
+        let mut tree = QuadTree::new_empty(0, 0, 128, 128, 3);
+        //crosses the vertical midline of the root, and the border between two leaves inside the top left quadrant
+        assert!(tree.insert_box(60, 10, 8, 4, 1));
+        assert!(tree.insert_box(30, 10, 4, 4, 2));
+        assert!(tree.insert_box(70, 70, 4, 4, 3));
+        assert_eq!(straddling_at_root(&tree), vec![1]);
+        let QuadTree::Quads(quads, _) = &tree else { panic!("the tree has quadrants") };
+        assert_eq!(straddling_at_root(&quads[0]), vec![2]);
+        //the box is in the straddling bucket, not in any leaf
+        assert!(tree.broad_phase(60, 10).is_empty() && tree.broad_phase(64, 10).is_empty());
+        //every query that overlaps the box finds it once, from either side of the edge or across it
+        for (x, y, w, h) in [(60, 10, 1, 1), (67, 13, 1, 1), (0, 0, 64, 64), (64, 0, 64, 64), (63, 12, 2, 0), (0, 0, 128, 128)] {
+            assert_eq!(tree.query_box(x, y, w, h).into_iter().filter(|&&i| i == 1).count(), 1, "{:?}", (x, y, w, h));
+            assert_eq!(tree.query_box_mut(x, y, w, h).into_iter().filter(|i| **i == 1).count(), 1, "{:?}", (x, y, w, h));
+        }
+        //the far edges are not part of the box
+        assert!(tree.query_box(68, 10, 4, 4).is_empty());
+        assert!(tree.query_box(50, 14, 30, 4).is_empty());
+        let mut all: Vec<usize> = tree.query_box(0, 0, 128, 128).into_iter().copied().collect();
+        all.sort();
+        assert_eq!(all, vec![1, 2, 3]);
+        assert_eq!(tree.remove(60, 10, |&i| i == 1), vec![1]);
+        assert!(straddling_at_root(&tree).is_empty());
+        assert!(tree.query_box(60, 10, 8, 4).is_empty());
+        assert_eq!(tree.remove(30, 10, |_| true), vec![2]);
+        assert_eq!(tree.query_box(0, 0, 128, 128), vec![&3]);
+    }
+}
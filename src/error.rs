@@ -0,0 +1,63 @@
+//! The errors returned by the try_ versions of the tree operations.
+
+use std::fmt;
+use super::coord::Coord;
+use super::rect::{self, Rect};
+
+/// Why a tree operation failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuadTreeError<CoordT: Coord = u16> {
+    /// The point is outside the root rect of the tree, given as (x, y, w, h)
+    OutOfBounds { x: CoordT, y: CoordT, rect: (CoordT, CoordT, CoordT, CoordT) },
+}
+
+impl<CoordT: Coord> fmt::Display for QuadTreeError<CoordT> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuadTreeError::OutOfBounds { x, y, rect } => write!(f, "point ({:?}, {:?}) is outside the tree rect {:?}", x, y, rect),
+        }
+    }
+}
+
+impl<CoordT: Coord> std::error::Error for QuadTreeError<CoordT> {}
+
+/// Return OutOfBounds if the point is outside the root rect
+pub(crate) fn check_bounds<CoordT: Coord>(root: Rect<CoordT>, x: CoordT, y: CoordT) -> Result<(), QuadTreeError<CoordT>> {
+    if rect::contains(root, x, y) {
+        Ok(())
+    } else {
+        Err(QuadTreeError::OutOfBounds { x, y, rect: root })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spatial_index::SpatialIndex;
+    use crate::{enum_quadtree, fixed_depth_quad_tree, loose_quadtree, morton_quadtree, uniform_grid};
+
+    /// The try_ operations of a backend at the lowest and highest i32 points give OutOfBounds instead of
+    /// panicking, and hand the item back
+    fn stray_points_give_errors<Index: SpatialIndex<usize, i32>>() {
+        let mut index = Index::new(-64, -64, 128, 128);
+        let root = (-64, -64, 128, 128);
+        for (x, y) in [(i32::MAX, i32::MAX), (i32::MIN, i32::MIN), (i32::MAX, 0), (0, i32::MIN), (i32::MIN, i32::MAX)] {
+            let error = QuadTreeError::OutOfBounds { x, y, rect: root };
+            assert_eq!(index.try_insert(x, y, 7), Err((7, error)));
+            assert_eq!(index.try_broad_phase(x, y).err(), Some(error));
+            assert_eq!(index.try_broad_phase_mut(x, y).err(), Some(error));
+            assert_eq!(check_bounds(root, x, y), Err(error));
+        }
+        assert_eq!(index.try_insert(-64, 63, 1), Ok(()));
+        assert_eq!(index.try_broad_phase(-64, 63).cloned(), Ok(vec![1]));
+    }
+
+    #[test]
+    fn try_operations_do_not_panic_on_stray_points() {
+        stray_points_give_errors::<fixed_depth_quad_tree::QuadTree<_, i32>>();
+        stray_points_give_errors::<enum_quadtree::QuadTree<_, i32>>();
+        stray_points_give_errors::<loose_quadtree::QuadTree<_, i32>>();
+        stray_points_give_errors::<morton_quadtree::QuadTree<_, i32>>();
+        stray_points_give_errors::<uniform_grid::Grid<_, i32>>();
+    }
+}
@@ -1,58 +1,142 @@
+use super::rect::{self, divide_into_4, quadrant_of, Rect};
+use super::nearest::KNearest;
+use super::bucket::{find_at, for_each_pair_in, pairs_in, recycle_vec, take_at, take_where, Straddling};
+use super::coord::Coord;
+use super::builder::{FromBuilder, OutOfBounds, QuadTreeBuilder};
+use super::spatial_index::{SpatialIndex, SpatialQueries};
+use super::error::{check_bounds, QuadTreeError};
+use std::thread;
+
 /// The QuadTree is a recursive data structure that divides a rectangle into 4 quadrants, each containing other quadrants, or a leaf
-/// The default depth is 3, which gives a 8x8 grid of leaves
-pub struct QuadTree<DataT>(QuadTreeImpl<DataT>);
-/// 4 quadrants, each containing 4 quadrants, each containing 4 Leafs (8x8 grid, see README.md)
-type QuadTreeDepth3<DataT> = [[[QuadTreeLeaf<DataT>; 4]; 4]; 4];
-type QuadTreeImpl<DataT>=QuadTreeDepth3<DataT>;
+/// The depth is part of the type. The default depth is 3, which gives a 8x8 grid of leaves, and depths 1 to 6 are
+/// supported (see Levels). Each depth is a different nesting of arrays, so the whole tree is one allocation free value.
+/// The leaves are stored inline and the tree is built on the stack, so deeper trees would overflow it. Use the enum
+/// or Morton tree for more than 64x64 leaves.
+/// Leaves are half open, a point on the border between two leaves belongs to the right or lower one.
+/// Items inserted with a box that does not fit inside a single leaf are kept in the straddling bucket.
+pub struct QuadTree<DataT, CoordT: Coord = u16, const DEPTH: usize = 3>(<Depth<DEPTH> as Levels>::Quads<DataT, CoordT>, Straddling<DataT, CoordT>) where Depth<DEPTH>: Levels;
+
+/// A fixed depth QuadTree with 2x2 leaves
+pub type QuadTreeDepth1<DataT, CoordT = u16> = QuadTree<DataT, CoordT, 1>;
+/// A fixed depth QuadTree with 4x4 leaves
+pub type QuadTreeDepth2<DataT, CoordT = u16> = QuadTree<DataT, CoordT, 2>;
+/// A fixed depth QuadTree with 8x8 leaves, see README.md
+pub type QuadTreeDepth3<DataT, CoordT = u16> = QuadTree<DataT, CoordT, 3>;
+/// A fixed depth QuadTree with 16x16 leaves
+pub type QuadTreeDepth4<DataT, CoordT = u16> = QuadTree<DataT, CoordT, 4>;
+/// A fixed depth QuadTree with 32x32 leaves
+pub type QuadTreeDepth5<DataT, CoordT = u16> = QuadTree<DataT, CoordT, 5>;
+/// A fixed depth QuadTree with 64x64 leaves
+pub type QuadTreeDepth6<DataT, CoordT = u16> = QuadTree<DataT, CoordT, 6>;
+
+/// Marker for the depth of a fixed depth QuadTree
+pub struct Depth<const DEPTH: usize>;
+
+/// Maps a depth to the nested arrays of quadrants of that depth.
+/// Depth 3 is 4 quadrants, each containing 4 quadrants, each containing 4 Leafs: [[[QuadTreeLeaf; 4]; 4]; 4]
+pub trait Levels {
+    type Quads<DataT, CoordT: Coord>: Quadrants<DataT = DataT, CoordT = CoordT>;
+    /// Empty all leaves and give them a new item type, keeping the capacity of their buckets
+    fn recycle<DataT, OtherT, CoordT: Coord>(quads: Self::Quads<DataT, CoordT>) -> Self::Quads<OtherT, CoordT>;
+}
+
+impl Levels for Depth<1> {
+    type Quads<DataT, CoordT: Coord> = [QuadTreeLeaf<DataT, CoordT>; 4];
+    fn recycle<DataT, OtherT, CoordT: Coord>(quads: Self::Quads<DataT, CoordT>) -> Self::Quads<OtherT, CoordT> {
+        quads.map(|leaf| leaf.recycle())
+    }
+}
+
+/// Each depth nests the quadrants of the depth above it in another array of 4
+macro_rules! impl_levels {
+    ($($depth:literal => $inner:literal),*) => {$(
+        impl Levels for Depth<$depth> {
+            type Quads<DataT, CoordT: Coord> = [<Depth<$inner> as Levels>::Quads<DataT, CoordT>; 4];
+            fn recycle<DataT, OtherT, CoordT: Coord>(quads: Self::Quads<DataT, CoordT>) -> Self::Quads<OtherT, CoordT> {
+                quads.map(<Depth<$inner> as Levels>::recycle)
+            }
+        }
+    )*};
+}
+
+impl_levels!(2 => 1, 3 => 2, 4 => 3, 5 => 4, 6 => 5);
 
 /// Leaf of the QuadTree
-pub struct QuadTreeLeaf<DataT> {
+pub struct QuadTreeLeaf<DataT, CoordT: Coord = u16> {
     //Bucket of data within the tree. This is intended to contain references to entities owned by the game model.
     pub data: Vec<DataT>,
     //For simplicity the, positions of the data elements are stored separately from the data.
     //TODO DataT: GetX + GetY
-    positions: Vec<(u16, u16)>,
+    positions: Vec<(CoordT, CoordT)>,
+    //Width and height of each item, items inserted as a point have a size of 0
+    sizes: Vec<(CoordT, CoordT)>,
     //The bounding box of the leaf
-    rect_x: u16, rect_y: u16, rect_w: u16, rect_h: u16,
+    rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT,
 }
 
 /// Trait for an array of 4 QuadTreeLeaves or 4 other Quadrants
 pub trait Quadrants{
     type DataT;
+    type CoordT: Coord;
     /// Construct 4 empty quadrants, each containing other quadrants, or a leaf
-    fn new_empty(rect_x: u16, rect_y: u16, rect_w: u16, rect_h: u16) -> Self;
+    fn new_empty(rect_x: Self::CoordT, rect_y: Self::CoordT, rect_w: Self::CoordT, rect_h: Self::CoordT) -> Self;
     /// Remove all points from all leaves
     fn clear(&mut self);
-    /// Insert a point into the correct leaf, or return false if it doesn't fit
-    fn can_insert(&mut self, x: u16, y: u16) -> bool;
-    // Insert a point into the correct leaf, or return false if it doesn't fit
-    fn insert(&mut self, x: u16, y: u16, data: Self::DataT) -> bool;
-    /// Return a reference to the leaf that contains the point
-    fn get_leaf_around(&self, x: u16, y: u16) -> Option<&QuadTreeLeaf<Self::DataT>>;
-    /// Return a mutable reference to the leaf that contains the point
-    fn get_mut_leaf_around(&mut self, x: u16, y: u16) -> Option<&mut QuadTreeLeaf<Self::DataT>>;
+    /// Insert a point into the correct leaf, or return false if it doesn't fit. node_rect is the bounds of these quadrants.
+    fn insert(&mut self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT, data: Self::DataT) -> bool;
+    /// Return a reference to the leaf that contains the point. node_rect is the bounds of these quadrants.
+    fn get_leaf_around(&self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT) -> Option<&QuadTreeLeaf<Self::DataT, Self::CoordT>>;
+    /// Return a mutable reference to the leaf that contains the point. node_rect is the bounds of these quadrants.
+    fn get_mut_leaf_around(&mut self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT) -> Option<&mut QuadTreeLeaf<Self::DataT, Self::CoordT>>;
+    /// Call f on every leaf below these quadrants, depth first, so the leaves come in Z-order
+    fn for_each_leaf_mut<'a>(&'a mut self, f: &mut impl FnMut(&'a mut QuadTreeLeaf<Self::DataT, Self::CoordT>));
+    /// The top left leaf below these quadrants
+    fn first_leaf(&self) -> &QuadTreeLeaf<Self::DataT, Self::CoordT>;
+    /// The bottom right leaf below these quadrants
+    fn last_leaf(&self) -> &QuadTreeLeaf<Self::DataT, Self::CoordT>;
+    /// The bounding box of these quadrants, computed from the top left and bottom right leaves
+    fn bounds(&self) -> Rect<Self::CoordT> {
+        let (rect_x, rect_y, _, _) = self.first_leaf().rect();
+        let (far_x, far_y, far_w, far_h) = self.last_leaf().rect();
+        (rect_x, rect_y, far_x - rect_x + far_w, far_y - rect_y + far_h)
+    }
+    /// Call f on every leaf whose bounds pass the touches test. node_rect is the bounds of these quadrants.
+    fn for_each_leaf_touching<'a>(&'a self, node_rect: Rect<Self::CoordT>, touches: &impl Fn(Rect<Self::CoordT>) -> bool, f: &mut impl FnMut(&'a QuadTreeLeaf<Self::DataT, Self::CoordT>));
+    /// Mutable version of for_each_leaf_touching
+    fn for_each_leaf_touching_mut<'a>(&'a mut self, node_rect: Rect<Self::CoordT>, touches: &impl Fn(Rect<Self::CoordT>) -> bool, f: &mut impl FnMut(&'a mut QuadTreeLeaf<Self::DataT, Self::CoordT>));
+    /// Call f on the leaves closest to the point first. Quadrants further away than bound (squared) are skipped.
+    /// f returns the new bound, and the latest bound is returned.
+    fn for_each_leaf_nearest_first<'a>(&'a self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT, bound: f64, f: &mut impl FnMut(&'a QuadTreeLeaf<Self::DataT, Self::CoordT>) -> f64) -> f64;
     // Used for debugging
     const DEPTH: usize;
 }
 
-///Split a rect into 4 quadrants. This is a utility function used by the QuadTree constructor
-fn divide_into_4(rect_x: u16, rect_y: u16, rect_w: u16, rect_h: u16) -> [(u16, u16, u16, u16); 4] {
-    let half_w = rect_w / 2;
-    let half_h = rect_h / 2;
-    [
-        (rect_x, rect_y, half_w, half_h),
-        (rect_x + half_w, rect_y, half_w, half_h),
-        (rect_x, rect_y + half_h, half_w, half_h),
-        (rect_x + half_w, rect_y + half_h, half_w, half_h)
-    ]
+///Order the 4 quadrants of a rect by their squared distance to the point, closest first
+fn nearest_first<CoordT: Coord>(rects: [Rect<CoordT>; 4], x: CoordT, y: CoordT) -> [(f64, usize); 4] {
+    let mut order = [0, 1, 2, 3].map(|i| (rect::distance_squared_to(rects[i], x, y).unwrap_or(f64::INFINITY), i));
+    order.sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    order
+}
+
+///Insert a point into the leaf that owns it, given the leaves below a node in Z-order. This descends like
+///Quadrants::insert, each quarter of the slice being one quadrant, so both put a point in the same leaf.
+fn insert_into_leaves<DataT, CoordT: Coord>(mut leaves: &mut [&mut QuadTreeLeaf<DataT, CoordT>], mut node_rect: Rect<CoordT>, x: CoordT, y: CoordT, data: DataT) -> bool {
+    while leaves.len() > 1 {
+        let i = quadrant_of(node_rect, x, y);
+        let quarter = leaves.len() / 4;
+        leaves = &mut leaves[i * quarter..(i + 1) * quarter];
+        node_rect = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[i];
+    }
+    leaves[0].insert(x, y, data)
 }
 
 /// An array of 4 Quadrants also implements Quadrants.
 /// Each depth of the tree is a different type so we use a recursive impl to implement each depth.
 impl<InnerQuadrants> Quadrants for [InnerQuadrants; 4] where InnerQuadrants: Quadrants {
     type DataT = InnerQuadrants::DataT;
+    type CoordT = InnerQuadrants::CoordT;
     /// Construct 4 empty quadrants, each containing other quadrants
-    fn new_empty(rect_x: u16, rect_y: u16, rect_w: u16, rect_h: u16) -> Self {
+    fn new_empty(rect_x: Self::CoordT, rect_y: Self::CoordT, rect_w: Self::CoordT, rect_h: Self::CoordT) -> Self {
         let rects = divide_into_4(rect_x, rect_y, rect_w, rect_h);
         [
             InnerQuadrants::new_empty(rects[0].0, rects[0].1, rects[0].2, rects[0].3),
@@ -66,57 +150,58 @@ impl<InnerQuadrants> Quadrants for [InnerQuadrants; 4] where InnerQuadrants: Qua
             quadrant_or_leaf.clear();
         }
     }
-    fn can_insert(&mut self, x: u16, y: u16) -> bool {
+    fn insert(&mut self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT, data: Self::DataT) -> bool {
+        let i = quadrant_of(node_rect, x, y);
+        self[i].insert(divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[i], x, y, data)
+    }
+    /// Return a reference to the vector of points in the leaf that contains the point
+    fn get_leaf_around(&self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT) -> Option<&QuadTreeLeaf<Self::DataT, Self::CoordT>> {
+        let i = quadrant_of(node_rect, x, y);
+        self[i].get_leaf_around(divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[i], x, y)
+    }
+    /// Return a mutable reference to the leaf that contains the point
+    fn get_mut_leaf_around(&mut self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT) -> Option<&mut QuadTreeLeaf<Self::DataT, Self::CoordT>> {
+        let i = quadrant_of(node_rect, x, y);
+        self[i].get_mut_leaf_around(divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3)[i], x, y)
+    }
+    fn for_each_leaf_mut<'a>(&'a mut self, f: &mut impl FnMut(&'a mut QuadTreeLeaf<Self::DataT, Self::CoordT>)) {
         for quadrant_or_leaf in self.iter_mut() {
-            //this will recurse down the tree until it finds a leaf
-            //short circuit if we find a leaf that accepts the point
-            if quadrant_or_leaf.can_insert(x, y) {
-                return true;
-            }
+            quadrant_or_leaf.for_each_leaf_mut(f);
         }
-        false
     }
-    fn insert(&mut self, x: u16, y: u16, data: Self::DataT) -> bool {
-        //unroll the top level for loop to show borrow checker insert is only called once
-        if self[0].can_insert(x, y) {
-            self[0].insert(x, y, data);
-            return true;
-        }
-        else if self[1].can_insert(x, y) {
-            self[1].insert(x, y, data);
-            return true;
-        }
-        else if self[2].can_insert(x, y) {
-            self[2].insert(x, y, data);
-            return true;
+    fn first_leaf(&self) -> &QuadTreeLeaf<Self::DataT, Self::CoordT> {
+        self[0].first_leaf()
+    }
+    fn last_leaf(&self) -> &QuadTreeLeaf<Self::DataT, Self::CoordT> {
+        self[3].last_leaf()
+    }
+    fn for_each_leaf_touching<'a>(&'a self, node_rect: Rect<Self::CoordT>, touches: &impl Fn(Rect<Self::CoordT>) -> bool, f: &mut impl FnMut(&'a QuadTreeLeaf<Self::DataT, Self::CoordT>)) {
+        if !touches(node_rect) {
+            return;
         }
-        else if self[3].can_insert(x, y) {
-            self[3].insert(x, y, data);
-            return true;
+        let rects = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3);
+        for (quadrant_or_leaf, quad_rect) in self.iter().zip(rects) {
+            quadrant_or_leaf.for_each_leaf_touching(quad_rect, touches, f);
         }
-        false
     }
-    /// Return a reference to the vector of points in the leaf that contains the point
-    fn get_leaf_around(&self, x: u16, y: u16) -> Option<&QuadTreeLeaf<Self::DataT>> {
-        for quadrant_or_leaf in self.iter() {
-            //this will recurse down the tree until it finds a leaf
-            //short circuit if we find a leaf that could contain the point
-            if let Some(vec) = quadrant_or_leaf.get_leaf_around(x, y) {
-                return Some(vec);
-            }
+    fn for_each_leaf_touching_mut<'a>(&'a mut self, node_rect: Rect<Self::CoordT>, touches: &impl Fn(Rect<Self::CoordT>) -> bool, f: &mut impl FnMut(&'a mut QuadTreeLeaf<Self::DataT, Self::CoordT>)) {
+        if !touches(node_rect) {
+            return;
+        }
+        let rects = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3);
+        for (quadrant_or_leaf, quad_rect) in self.iter_mut().zip(rects) {
+            quadrant_or_leaf.for_each_leaf_touching_mut(quad_rect, touches, f);
         }
-        None
     }
-    /// Return a mutable reference to the leaf that contains the point
-    fn get_mut_leaf_around(&mut self, x: u16, y: u16) -> Option<&mut QuadTreeLeaf<Self::DataT>> {
-        for quadrant_or_leaf in self.iter_mut() {
-            //this will recurse down the tree until it finds a leaf
-            //short circuit if we find a leaf that could contain the point
-            if let Some(vec) = quadrant_or_leaf.get_mut_leaf_around(x, y) {
-                return Some(vec);
+    fn for_each_leaf_nearest_first<'a>(&'a self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT, mut bound: f64, f: &mut impl FnMut(&'a QuadTreeLeaf<Self::DataT, Self::CoordT>) -> f64) -> f64 {
+        let rects = divide_into_4(node_rect.0, node_rect.1, node_rect.2, node_rect.3);
+        for (d2, i) in nearest_first(rects, x, y) {
+            if d2 >= bound {
+                break;
             }
+            bound = self[i].for_each_leaf_nearest_first(rects[i], x, y, bound, f);
         }
-        None
+        bound
     }
 
     const DEPTH: usize = InnerQuadrants::DEPTH + 1;
@@ -124,10 +209,11 @@ impl<InnerQuadrants> Quadrants for [InnerQuadrants; 4] where InnerQuadrants: Qua
 
 /// An array of 4 QuadTreeLeafs implements Quadrants.
 /// This is the bottom of the recursive impl chain, it interacts with the leaf instead of another quadrant.
-impl<DataT> Quadrants for [QuadTreeLeaf<DataT>; 4] {
+impl<DataT, CoordT: Coord> Quadrants for [QuadTreeLeaf<DataT, CoordT>; 4] {
     type DataT = DataT;
+    type CoordT = CoordT;
     /// Construct 4 empty leaves
-    fn new_empty(rect_x: u16, rect_y: u16, rect_w: u16, rect_h: u16) -> Self {
+    fn new_empty(rect_x: Self::CoordT, rect_y: Self::CoordT, rect_w: Self::CoordT, rect_h: Self::CoordT) -> Self {
         let rects = divide_into_4(rect_x, rect_y, rect_w, rect_h);
         [
             QuadTreeLeaf::new_empty(rects[0].0, rects[0].1, rects[0].2, rects[0].3),
@@ -141,143 +227,991 @@ impl<DataT> Quadrants for [QuadTreeLeaf<DataT>; 4] {
             leaf.clear();
         }
     }
-    fn can_insert(&mut self, x: u16, y: u16) -> bool {
+    fn insert(&mut self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT, data: Self::DataT) -> bool {
+        //the leaf checks the point itself, this catches points outside the tree
+        self[quadrant_of(node_rect, x, y)].insert(x, y, data)
+    }
+    /// Return a reference to the vector of points in the leaf that contains the point
+    fn get_leaf_around(&self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT) -> Option<&QuadTreeLeaf<DataT, Self::CoordT>> {
+        Some(&self[quadrant_of(node_rect, x, y)]).filter(|leaf| leaf.can_insert(x, y))
+    }
+    /// Return a mutable reference to the leaf that contains the point
+    fn get_mut_leaf_around(&mut self, node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT) -> Option<&mut QuadTreeLeaf<Self::DataT, Self::CoordT>> {
+        Some(&mut self[quadrant_of(node_rect, x, y)]).filter(|leaf| leaf.can_insert(x, y))
+    }
+    fn for_each_leaf_mut<'a>(&'a mut self, f: &mut impl FnMut(&'a mut QuadTreeLeaf<Self::DataT, Self::CoordT>)) {
         for leaf in self.iter_mut() {
-            //short circuit if we find a leaf that accepts the point
-            if leaf.can_insert(x, y) {
-                return true;
-            }
+            f(leaf);
         }
-        false
     }
-    fn insert(&mut self, x: u16, y: u16, data: Self::DataT) -> bool {
-        //unroll the top level for loop to show borrow checker insert is only called once
-        if self[0].can_insert(x, y) {
-            self[0].insert(x, y, data);
-            return true;
-        }
-        else if self[1].can_insert(x, y) {
-            self[1].insert(x, y, data);
-            return true;
-        }
-        else if self[2].can_insert(x, y) {
-            self[2].insert(x, y, data);
-            return true;
-        }
-        else if self[3].can_insert(x, y) {
-            self[3].insert(x, y, data);
-            return true;
-        }
-        false
+    fn first_leaf(&self) -> &QuadTreeLeaf<Self::DataT, Self::CoordT> {
+        &self[0]
     }
-    /// Return a reference to the vector of points in the leaf that contains the point
-    fn get_leaf_around(&self, x: u16, y: u16) -> Option<&QuadTreeLeaf<DataT>> {
+    fn last_leaf(&self) -> &QuadTreeLeaf<Self::DataT, Self::CoordT> {
+        &self[3]
+    }
+    fn for_each_leaf_touching<'a>(&'a self, _node_rect: Rect<Self::CoordT>, touches: &impl Fn(Rect<Self::CoordT>) -> bool, f: &mut impl FnMut(&'a QuadTreeLeaf<Self::DataT, Self::CoordT>)) {
+        //the leaves know their own bounds, so node_rect is not needed here
         for leaf in self.iter() {
-            //short circuit if we find a leaf that could contain the point
-            if leaf.can_insert(x, y) {
-                return Some(&leaf);
+            if touches((leaf.rect_x, leaf.rect_y, leaf.rect_w, leaf.rect_h)) {
+                f(leaf);
             }
         }
-        None
     }
-    /// Return a mutable reference to the leaf that contains the point
-    fn get_mut_leaf_around(&mut self, x: u16, y: u16) -> Option<&mut QuadTreeLeaf<Self::DataT>> {
+    fn for_each_leaf_touching_mut<'a>(&'a mut self, _node_rect: Rect<Self::CoordT>, touches: &impl Fn(Rect<Self::CoordT>) -> bool, f: &mut impl FnMut(&'a mut QuadTreeLeaf<Self::DataT, Self::CoordT>)) {
         for leaf in self.iter_mut() {
-            //short circuit if we find a leaf that could contain the point
-            if leaf.can_insert(x, y) {
-                return Some(leaf);
+            if touches((leaf.rect_x, leaf.rect_y, leaf.rect_w, leaf.rect_h)) {
+                f(leaf);
+            }
+        }
+    }
+    fn for_each_leaf_nearest_first<'a>(&'a self, _node_rect: Rect<Self::CoordT>, x: Self::CoordT, y: Self::CoordT, mut bound: f64, f: &mut impl FnMut(&'a QuadTreeLeaf<Self::DataT, Self::CoordT>) -> f64) -> f64 {
+        let rects = self.each_ref().map(|leaf| (leaf.rect_x, leaf.rect_y, leaf.rect_w, leaf.rect_h));
+        for (d2, i) in nearest_first(rects, x, y) {
+            if d2 >= bound {
+                break;
             }
+            bound = f(&self[i]);
         }
-        None
+        bound
     }
     //This is a Quadrant of 4 leaves, so the depth is 1
     const DEPTH: usize = 1;
 }
 
 /// A QuadTree leaf with a constructor and a method to insert a point
-impl<DataT> QuadTreeLeaf<DataT> {
-    fn new_empty(rect_x: u16, rect_y: u16, rect_w: u16, rect_h: u16) -> Self {
-        QuadTreeLeaf {data: Vec::new(), positions: Vec::new(), rect_x, rect_y, rect_w, rect_h}
+impl<DataT, CoordT: Coord> QuadTreeLeaf<DataT, CoordT> {
+    fn new_empty(rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT) -> Self {
+        QuadTreeLeaf {data: Vec::new(), positions: Vec::new(), sizes: Vec::new(), rect_x, rect_y, rect_w, rect_h}
     }
     fn clear(&mut self) {
         self.data.clear();
         self.positions.clear();
+        self.sizes.clear();
+    }
+    fn rect(&self) -> Rect<CoordT> {
+        (self.rect_x, self.rect_y, self.rect_w, self.rect_h)
+    }
+    fn recycle<OtherT>(mut self) -> QuadTreeLeaf<OtherT, CoordT> {
+        self.positions.clear();
+        self.sizes.clear();
+        let QuadTreeLeaf { data, positions, sizes, rect_x, rect_y, rect_w, rect_h } = self;
+        QuadTreeLeaf { data: recycle_vec(data), positions, sizes, rect_x, rect_y, rect_w, rect_h }
     }
-    fn can_insert(&self, x: u16, y: u16) -> bool {
-        x >= self.rect_x && x <= self.rect_x + self.rect_w && y >= self.rect_y && y <= self.rect_y + self.rect_h
+    fn reserve(&mut self, additional: usize) {
+        self.data.reserve(additional);
+        self.positions.reserve(additional);
+        self.sizes.reserve(additional);
     }
-    fn insert(&mut self, x: u16, y: u16, data: DataT) -> bool {
+    fn can_insert(&self, x: CoordT, y: CoordT) -> bool {
+        rect::contains(self.rect(), x, y)
+    }
+    fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
         if self.can_insert(x, y) {
             self.data.push(data);
             self.positions.push((x, y));
+            self.sizes.push((CoordT::ZERO, CoordT::ZERO));
             true
         } else {
             false
         }
     }
+    /// Remove every item for which the predicate returns true, and return the removed items.
+    /// The order of the remaining items is not preserved.
+    fn remove_where(&mut self, predicate: &mut impl FnMut(&DataT, CoordT, CoordT) -> bool) -> Vec<DataT> {
+        take_where(&mut self.data, &mut self.positions, &mut self.sizes, predicate)
+    }
+}
+
+/// The depth of this tree is part of its type, so the depth or leaf size of the builder is not used
+impl<DataT, CoordT: Coord, const DEPTH: usize> FromBuilder<DataT, CoordT> for QuadTree<DataT, CoordT, DEPTH> where Depth<DEPTH>: Levels {
+    fn from_builder(builder: &QuadTreeBuilder<CoordT>) -> Self {
+        let mut tree = QuadTree::new_empty(builder.rect_x, builder.rect_y, builder.rect_w, builder.rect_h);
+        tree.0.for_each_leaf_mut(&mut |leaf| leaf.reserve(builder.leaf_capacity));
+        tree
+    }
+    fn insert_point(&mut self, x: CoordT, y: CoordT, data: DataT) {
+        self.insert(x, y, data);
+    }
+}
+
+impl<DataT, CoordT: Coord, const DEPTH: usize> SpatialIndex<DataT, CoordT> for QuadTree<DataT, CoordT, DEPTH> where Depth<DEPTH>: Levels {
+    fn clear(&mut self) {
+        self.clear();
+    }
+    fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.insert(x, y, data)
+    }
+    fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        self.try_insert(x, y, data)
+    }
+    fn remove(&mut self, x: CoordT, y: CoordT, predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        self.remove(x, y, predicate)
+    }
+    fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.broad_phase(x, y)
+    }
+    fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.broad_phase_mut(x, y)
+    }
+    fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase(x, y)
+    }
+    fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase_mut(x, y)
+    }
+    fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        self.query_rect(x, y, w, h)
+    }
+}
+
+impl<DataT, CoordT: Coord, const DEPTH: usize> SpatialQueries<DataT, CoordT> for QuadTree<DataT, CoordT, DEPTH> where Depth<DEPTH>: Levels {
+    fn query_rect_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        self.query_rect_mut(x, y, w, h)
+    }
+    fn query_box(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        self.query_box(x, y, w, h)
+    }
+    fn query_box_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        self.query_box_mut(x, y, w, h)
+    }
+    fn query_radius(&self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&DataT> {
+        self.query_radius(center_x, center_y, radius)
+    }
+    fn query_radius_mut(&mut self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&mut DataT> {
+        self.query_radius_mut(center_x, center_y, radius)
+    }
+    fn k_nearest(&self, x: CoordT, y: CoordT, k: usize) -> Vec<(f64, &DataT)> {
+        self.k_nearest(x, y, k)
+    }
+    fn broad_phase_neighbourhood(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        self.broad_phase_neighbourhood(x, y, w, h)
+    }
+    fn broad_phase_neighbourhood_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        self.broad_phase_neighbourhood_mut(x, y, w, h)
+    }
+    fn for_each_pair_mut(&mut self, f: impl FnMut(&mut DataT, &mut DataT)) {
+        self.for_each_pair_mut(f)
+    }
 }
 
-use super::GetX;
-use super::GetY;
+use super::{GetX, GetY, GetWidth, GetHeight};
 
-impl<DataT> QuadTree<DataT> {
+impl<DataT, CoordT: Coord, const DEPTH: usize> QuadTree<DataT, CoordT, DEPTH> where Depth<DEPTH>: Levels {
+    /// How many times the tree is divided into quadrants
+    pub const DEPTH: usize = DEPTH;
+    /// The number of leaves along each side of the tree
+    pub const LEAVES_PER_SIDE: usize = 1 << DEPTH;
+    /// The width or height of the top left leaf in a tree of the given integer width or height. The right and
+    /// bottom quadrants get the extra unit of odd sizes, so the other leaves can be one unit wider or higher.
+    pub const fn leaf_size(tree_size: usize) -> usize {
+        tree_size >> DEPTH
+    }
     /// Construct a new QuadTree with the given bounds
-    pub fn new_empty(rect_x: u16, rect_y: u16, rect_w: u16, rect_h: u16) -> Self {
-        Self(QuadTreeImpl::new_empty(rect_x, rect_y, rect_w, rect_h))
+    pub fn new_empty(rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT) -> Self {
+        Self(Quadrants::new_empty(rect_x, rect_y, rect_w, rect_h), Straddling::new())
     }
-    /// Convienience function for get_leaf_around that returns a reference to the vec of data
-    pub fn broad_phase(&self, x: u16, y: u16) -> &Vec<DataT> {
-        &self.0.get_leaf_around(x, y).unwrap().data
+    /// Remove all points from all leaves
+    pub fn clear(&mut self) {
+        self.0.clear();
+        self.1.clear();
+    }
+    /// Empty the tree and give it a new item type, keeping the capacity of its buckets.
+    /// This lets a tree of references into a model be filled again for the next borrow of the model.
+    pub fn recycle<OtherT>(self) -> QuadTree<OtherT, CoordT, DEPTH> {
+        QuadTree(<Depth<DEPTH> as Levels>::recycle(self.0), self.1.recycle())
     }
-    /// Convienience function for get_mut_leaf_around that returns a mutable reference to the vec of data
-    pub fn broad_phase_mut(&mut self, x: u16, y: u16) -> &mut Vec<DataT> {
-        &mut self.0.get_mut_leaf_around(x, y).unwrap().data
+    /// Insert a point into the correct leaf, or return false if it doesn't fit
+    pub fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.try_insert(x, y, data).is_ok()
+    }
+    /// Insert a point into the correct leaf. If it doesn't fit, the data is given back with the error, so
+    /// the caller can keep it or store it elsewhere.
+    pub fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        let bounds = self.0.bounds();
+        if let Err(error) = check_bounds(bounds, x, y) {
+            return Err((data, error));
+        }
+        self.0.insert(bounds, x, y, data);
+        Ok(())
+    }
+    /// Insert an item with a bounding box. The item is stored once: in the leaf that contains its top left
+    /// corner if the whole box fits in that leaf, otherwise in the straddling bucket of the tree.
+    /// Returns false if the top left corner is outside the tree.
+    pub fn insert_box(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT, data: DataT) -> bool {
+        self.try_insert_box(x, y, w, h, data).is_ok()
+    }
+    /// Insert an item with a bounding box like insert_box. If the top left corner is outside the tree, the data
+    /// is given back with the error.
+    pub fn try_insert_box(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        let item = (x, y, w, h);
+        let bounds = self.0.bounds();
+        match self.0.get_mut_leaf_around(bounds, x, y) {
+            Some(leaf) if rect::fits(item, leaf.rect()) => {
+                leaf.data.push(data);
+                leaf.positions.push((x, y));
+                leaf.sizes.push((w, h));
+            }
+            Some(_) => self.1.push(item, data),
+            None => return Err((data, QuadTreeError::OutOfBounds { x, y, rect: bounds })),
+        }
+        Ok(())
+    }
+    /// Call f with the data, positions and sizes of the straddling bucket and of every leaf whose bounds pass
+    /// the touches test
+    fn for_each_bucket_touching<'a>(&'a self, touches: &impl Fn(Rect<CoordT>) -> bool, f: &mut impl FnMut(&'a [DataT], &'a [(CoordT, CoordT)], &'a [(CoordT, CoordT)])) {
+        let bounds = self.0.bounds();
+        if touches(bounds) {
+            f(&self.1.data, &self.1.positions, &self.1.sizes);
+        }
+        self.0.for_each_leaf_touching(bounds, touches, &mut |leaf| f(&leaf.data, &leaf.positions, &leaf.sizes));
+    }
+    /// Mutable version of for_each_bucket_touching
+    fn for_each_bucket_touching_mut<'a>(&'a mut self, touches: &impl Fn(Rect<CoordT>) -> bool, f: &mut impl FnMut(&'a mut [DataT], &'a [(CoordT, CoordT)], &'a [(CoordT, CoordT)])) {
+        let bounds = self.0.bounds();
+        if touches(bounds) {
+            f(&mut self.1.data, &self.1.positions, &self.1.sizes);
+        }
+        self.0.for_each_leaf_touching_mut(bounds, touches, &mut |leaf| f(&mut leaf.data, &leaf.positions, &leaf.sizes));
+    }
+    /// Return every item whose box overlaps the query box. Items inserted as a point have a size of 0.
+    /// Every item is stored once, so no item is returned twice.
+    pub fn query_box(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        //boxes hanging over the edge of the tree are kept at the root, so it is always visited
+        let bounds = self.0.bounds();
+        self.for_each_bucket_touching(&|node| node == bounds || rect::overlaps(node, query), &mut |data, positions, sizes| {
+            for ((item, &(item_x, item_y)), &(item_w, item_h)) in data.iter().zip(positions).zip(sizes) {
+                if rect::overlaps(query, (item_x, item_y, item_w, item_h)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Mutable version of query_box
+    pub fn query_box_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        //boxes hanging over the edge of the tree are kept at the root, so it is always visited
+        let bounds = self.0.bounds();
+        self.for_each_bucket_touching_mut(&|node| node == bounds || rect::overlaps(node, query), &mut |data, positions, sizes| {
+            for ((item, &(item_x, item_y)), &(item_w, item_h)) in data.iter_mut().zip(positions).zip(sizes) {
+                if rect::overlaps(query, (item_x, item_y, item_w, item_h)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Convienience function for get_leaf_around that returns a reference to the vec of data. Only the items
+    /// stored in the leaf are in it, so boxes that insert_box kept in a straddling bucket above the leaf are
+    /// missing, use broad_phase_neighbourhood or query_box to find those.
+    /// Panics if the point is outside the tree, see try_broad_phase.
+    pub fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.try_broad_phase(x, y).unwrap()
+    }
+    /// Convienience function for get_mut_leaf_around that returns a mutable reference to the vec of data.
+    /// Panics if the point is outside the tree, see try_broad_phase_mut.
+    pub fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.try_broad_phase_mut(x, y).unwrap()
+    }
+    /// Return a reference to the vec of data of the leaf that contains the point, or OutOfBounds. Like
+    /// broad_phase, this leaves out the straddling boxes.
+    pub fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        let bounds = self.0.bounds();
+        self.0.get_leaf_around(bounds, x, y).map(|leaf| &leaf.data).ok_or(QuadTreeError::OutOfBounds { x, y, rect: bounds })
+    }
+    /// Mutable version of try_broad_phase
+    pub fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        let bounds = self.0.bounds();
+        self.0.get_mut_leaf_around(bounds, x, y).map(|leaf| &mut leaf.data).ok_or(QuadTreeError::OutOfBounds { x, y, rect: bounds })
+    }
+    /// Return every item of every leaf that the box touches, and the straddling items. Unlike broad_phase, this
+    /// includes the neighbouring leaves when the box straddles a leaf border. Each leaf is visited once, so no item
+    /// is returned twice.
+    pub fn broad_phase_neighbourhood(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(&|node| rect::overlaps(node, query), &mut |data, _, _| found.extend(data));
+        found
+    }
+    /// Mutable version of broad_phase_neighbourhood
+    pub fn broad_phase_neighbourhood_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching_mut(&|node| rect::overlaps(node, query), &mut |data, _, _| found.extend(data));
+        found
+    }
+    /// Like broad_phase_neighbourhood, on a world that wraps around at the edges of the tree, so the leaves on the
+    /// far side of an edge are included, see neighbourhood_wrapped. Each leaf is still visited once.
+    pub fn broad_phase_neighbourhood_wrapped(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let bounds = self.0.bounds();
+        let pieces = rect::wrap_pieces((x, y, w, h), bounds);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(&|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, _, _| found.extend(data));
+        found
+    }
+    /// Mutable version of broad_phase_neighbourhood_wrapped
+    pub fn broad_phase_neighbourhood_wrapped_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let bounds = self.0.bounds();
+        let pieces = rect::wrap_pieces((x, y, w, h), bounds);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching_mut(&|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, _, _| found.extend(data));
+        found
+    }
+    /// Call f on the items of every leaf, spreading the leaves over the given number of threads. Every thread
+    /// gets a run of neighbouring leaves, and every leaf goes to one thread, so f has the only access to its items.
+    /// Items inserted with insert_box, like the entities of with_tree_boxes, have their whole box in their leaf,
+    /// so they can only touch items of the same leaf or straddling items. Pairs with a straddling item span leaves,
+    /// and possibly threads, so they are left to for_each_straddling_pair_mut, which is run after this returns.
+    /// Items inserted as a point have no box, so when they stand for something larger, pairs across leaves are missed.
+    /// The leaves are handed out as slices, so items can be changed but not added or removed, which would leave
+    /// them without a position.
+    pub fn par_for_each_leaf_mut(&mut self, threads: usize, f: impl Fn(&mut [DataT]) + Sync) where DataT: Send, CoordT: Send {
+        let mut leaves = Vec::with_capacity(1 << (2 * DEPTH));
+        self.0.for_each_leaf_mut(&mut |leaf| leaves.push(leaf));
+        //the leaves are in Z-order, so a run of them is a compact area of the tree
+        let run_len = leaves.len().div_ceil(threads.max(1));
+        let f = &f;
+        thread::scope(|scope| {
+            for run in leaves.chunks_mut(run_len) {
+                scope.spawn(move || {
+                    for leaf in run {
+                        f(&mut leaf.data);
+                    }
+                });
+            }
+        });
+    }
+    /// Call f on every pair of a straddling item and an item whose box overlaps it, the straddling item first.
+    /// Together with the pairs inside the leaves, see par_for_each_leaf_mut, this covers every overlapping pair once.
+    pub fn for_each_straddling_pair_mut(&mut self, mut f: impl FnMut(&mut DataT, &mut DataT)) {
+        let QuadTree(quads, straddling) = self;
+        let bounds = quads.bounds();
+        for i in 0..straddling.data.len() {
+            let (x, y) = straddling.positions[i];
+            let (w, h) = straddling.sizes[i];
+            let item = (x, y, w, h);
+            let (data, later) = straddling.data.split_at_mut(i + 1);
+            let this = &mut data[i];
+            for ((other, &(other_x, other_y)), &(other_w, other_h)) in later.iter_mut().zip(&straddling.positions[i + 1..]).zip(&straddling.sizes[i + 1..]) {
+                if rect::overlaps(item, (other_x, other_y, other_w, other_h)) {
+                    f(this, other);
+                }
+            }
+            quads.for_each_leaf_touching_mut(bounds, &|node| rect::overlaps(node, item), &mut |leaf| {
+                for ((other, &(other_x, other_y)), &(other_w, other_h)) in leaf.data.iter_mut().zip(&leaf.positions).zip(&leaf.sizes) {
+                    if rect::overlaps(item, (other_x, other_y, other_w, other_h)) {
+                        f(this, other);
+                    }
+                }
+            });
+        }
+    }
+    /// Call f on every unordered pair of candidates once: every pair of items in the same leaf, like broad_phase,
+    /// and every pair of a straddling item with an item whose box overlaps it, the straddling item first.
+    /// Items inserted with insert_box, like the entities of with_tree_boxes, have their whole box in their leaf,
+    /// so this covers every overlapping pair. Items inserted as a point have no box, so for them only pairs in the
+    /// same leaf are found.
+    pub fn for_each_pair_mut(&mut self, mut f: impl FnMut(&mut DataT, &mut DataT)) {
+        self.0.for_each_leaf_mut(&mut |leaf| for_each_pair_in(&mut leaf.data, &mut f));
+        self.for_each_straddling_pair_mut(f);
+    }
+    /// Return the pairs of for_each_pair_mut
+    pub fn candidate_pairs(&self) -> Vec<(&DataT, &DataT)> {
+        let mut pairs = Vec::new();
+        let bounds = self.0.bounds();
+        self.0.for_each_leaf_touching(bounds, &|_| true, &mut |leaf| pairs_in(&leaf.data, &mut pairs));
+        let straddling = &self.1;
+        for (i, (this, (&(x, y), &(w, h)))) in straddling.data.iter().zip(straddling.positions.iter().zip(&straddling.sizes)).enumerate() {
+            let item = (x, y, w, h);
+            for ((other, &(other_x, other_y)), &(other_w, other_h)) in straddling.data[i + 1..].iter().zip(&straddling.positions[i + 1..]).zip(&straddling.sizes[i + 1..]) {
+                if rect::overlaps(item, (other_x, other_y, other_w, other_h)) {
+                    pairs.push((this, other));
+                }
+            }
+            self.0.for_each_leaf_touching(bounds, &|node| rect::overlaps(node, item), &mut |leaf| {
+                for ((other, &(other_x, other_y)), &(other_w, other_h)) in leaf.data.iter().zip(&leaf.positions).zip(&leaf.sizes) {
+                    if rect::overlaps(item, (other_x, other_y, other_w, other_h)) {
+                        pairs.push((this, other));
+                    }
+                }
+            });
+        }
+        pairs
+    }
+    /// Remove the items stored at exactly (x, y) for which the predicate returns true, and return them.
+    /// Only the leaf that owns the point and the straddling bucket are searched.
+    pub fn remove(&mut self, x: CoordT, y: CoordT, mut predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        let mut at_point = |data: &DataT, data_x, data_y| data_x == x && data_y == y && predicate(data);
+        let mut removed = self.1.remove_where(&mut at_point);
+        if let Some(leaf) = self.0.get_mut_leaf_around(self.0.bounds(), x, y) {
+            removed.append(&mut leaf.remove_where(&mut at_point));
+        }
+        removed
+    }
+    /// Return every item whose stored position is inside the rect. Only the leaves that touch the rect are visited.
+    pub fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(&|node| rect::overlaps(node, query), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                if rect::contains(query, item_x, item_y) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return a mutable reference to every item whose stored position is inside the rect
+    pub fn query_rect_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching_mut(&|node| rect::overlaps(node, query), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter_mut().zip(positions) {
+                if rect::contains(query, item_x, item_y) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return every item whose stored position is within radius of the center. Quadrants that do not
+    /// intersect the circle are skipped.
+    pub fn query_radius(&self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(&|node| rect::overlaps_circle(node, center_x, center_y, radius), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                if rect::distance_squared(center_x, center_y, item_x, item_y) <= max_d2 {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return a mutable reference to every item whose stored position is within radius of the center
+    pub fn query_radius_mut(&mut self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&mut DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let mut found = Vec::new();
+        self.for_each_bucket_touching_mut(&|node| rect::overlaps_circle(node, center_x, center_y, radius), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter_mut().zip(positions) {
+                if rect::distance_squared(center_x, center_y, item_x, item_y) <= max_d2 {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Like query_rect, on a world that wraps around at the edges of the tree, so a rect that runs past the far
+    /// edge goes on from the near one. The rect can start outside the tree, it is wrapped into it first.
+    pub fn query_rect_wrapped(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let bounds = self.0.bounds();
+        let pieces = rect::wrap_pieces((x, y, w, h), bounds);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(&|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                if pieces.iter().any(|&piece| rect::contains(piece, item_x, item_y)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Mutable version of query_rect_wrapped
+    pub fn query_rect_wrapped_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let bounds = self.0.bounds();
+        let pieces = rect::wrap_pieces((x, y, w, h), bounds);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching_mut(&|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter_mut().zip(positions) {
+                if pieces.iter().any(|&piece| rect::contains(piece, item_x, item_y)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Like query_radius, on a world that wraps around at the edges of the tree. Distances are measured the
+    /// short way around, and the leaves are picked by the square around the circle.
+    pub fn query_radius_wrapped(&self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let bounds = self.0.bounds();
+        let (center_x, center_y, pieces) = rect::wrap_circle(bounds, center_x, center_y, radius);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching(&|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                if rect::distance_squared_wrapped(bounds, center_x, center_y, item_x, item_y) <= max_d2 {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Mutable version of query_radius_wrapped
+    pub fn query_radius_wrapped_mut(&mut self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&mut DataT> {
+        let max_d2 = radius.to_f64() * radius.to_f64();
+        let bounds = self.0.bounds();
+        let (center_x, center_y, pieces) = rect::wrap_circle(bounds, center_x, center_y, radius);
+        let mut found = Vec::new();
+        self.for_each_bucket_touching_mut(&|node| pieces.iter().any(|&piece| rect::overlaps(node, piece)), &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter_mut().zip(positions) {
+                if rect::distance_squared_wrapped(bounds, center_x, center_y, item_x, item_y) <= max_d2 {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return the closest item to the point and its squared distance, or None if the tree is empty
+    pub fn nearest(&self, x: CoordT, y: CoordT) -> Option<(f64, &DataT)> {
+        self.k_nearest(x, y, 1).into_iter().next()
+    }
+    /// Return up to k items closest to the point with their squared distances, closest first.
+    /// Quadrants are visited closest first, and skipped once they are further away than the k-th item found so far.
+    pub fn k_nearest(&self, x: CoordT, y: CoordT, k: usize) -> Vec<(f64, &DataT)> {
+        let mut best = KNearest::new(k);
+        for (item, &(item_x, item_y)) in self.1.data.iter().zip(&self.1.positions) {
+            best.offer(rect::distance_squared(x, y, item_x, item_y), item);
+        }
+        self.0.for_each_leaf_nearest_first(self.0.bounds(), x, y, best.bound(), &mut |leaf| {
+            for (item, &(item_x, item_y)) in leaf.data.iter().zip(&leaf.positions) {
+                best.offer(rect::distance_squared(x, y, item_x, item_y), item);
+            }
+            best.bound()
+        });
+        best.into_vec()
+    }
+    /// Move the item stored at (old_x, old_y) for which the matcher returns true to (new_x, new_y).
+    /// The position is updated in place when the item stays in its leaf, otherwise the item is taken out and
+    /// inserted again with its size. Returns false, leaving the tree unchanged, if there is no such item or the
+    /// new position is outside the tree.
+    pub fn relocate(&mut self, old_x: CoordT, old_y: CoordT, new_x: CoordT, new_y: CoordT, mut matcher: impl FnMut(&DataT) -> bool) -> bool {
+        //the leaf of the new position is found the way insert finds it, so a point on a border gets the leaf right of or below it
+        let Some(new_leaf) = self.0.get_leaf_around(self.0.bounds(), new_x, new_y).map(|leaf| leaf.rect()) else { return false };
+        let Some(leaf) = self.0.get_mut_leaf_around(self.0.bounds(), old_x, old_y) else { return false };
+        let Some(index) = find_at(&leaf.data, &leaf.positions, old_x, old_y, &mut matcher) else {
+            return self.relocate_straddling(old_x, old_y, new_x, new_y, &mut matcher);
+        };
+        let (w, h) = leaf.sizes[index];
+        if leaf.rect() == new_leaf && rect::fits((new_x, new_y, w, h), new_leaf) {
+            leaf.positions[index] = (new_x, new_y);
+            return true;
+        }
+        let (data, _) = take_at(&mut leaf.data, &mut leaf.positions, &mut leaf.sizes, index);
+        self.insert_box(new_x, new_y, w, h, data)
+    }
+    /// Move an item of the straddling bucket by taking it out and inserting it again
+    fn relocate_straddling(&mut self, old_x: CoordT, old_y: CoordT, new_x: CoordT, new_y: CoordT, matcher: &mut impl FnMut(&DataT) -> bool) -> bool {
+        let Some(index) = find_at(&self.1.data, &self.1.positions, old_x, old_y, matcher) else { return false };
+        let (data, (w, h)) = take_at(&mut self.1.data, &mut self.1.positions, &mut self.1.sizes, index);
+        self.insert_box(new_x, new_y, w, h, data)
+    }
+    /// Remove every item in the tree for which the predicate returns true, and return them.
+    /// The predicate is given the item and its stored position.
+    pub fn remove_where(&mut self, mut predicate: impl FnMut(&DataT, CoordT, CoordT) -> bool) -> Vec<DataT> {
+        let mut removed = self.1.remove_where(&mut predicate);
+        self.0.for_each_leaf_mut(&mut |leaf| removed.append(&mut leaf.remove_where(&mut predicate)));
+        removed
     }
 }
 
-impl<Entity: GetX+GetY> QuadTree<Entity> {
+impl<Entity: GetX<CoordT>+GetY<CoordT>, CoordT: Coord, const DEPTH: usize> QuadTree<Entity, CoordT, DEPTH> where Depth<DEPTH>: Levels {
 
     /// Rebuild a QuadTree from a model. This is used to update the tree after the model has changed.
     /// It clears the tree, then puts references to the entities back in it, however it does not
     /// change the capacities of the underlying Vecs.
-    pub fn rebuild_from_model(tree: &mut QuadTree<&mut Entity>, model: &mut Vec<Entity>) {
-        tree.0.clear();
-        for i in 0..model.len() {
-            let entity = &mut model[i] as *mut Entity;
-            //// SAFETY: This is safe because the tree is cleared before being filled.
+    /// This aliases the model through raw pointers, see with_tree for a version tied to the borrow of the model.
+    /// Returns how many entities were left out because they are outside the tree.
+    ///
+    /// # Safety
+    /// The references put in the tree are not tied to the borrow of the model. Until the tree is cleared or
+    /// rebuilt, the model must not be moved, dropped or resized, and an entity must not be reached through the
+    /// tree while a reference to it taken from the model is alive, or the other way around.
+    pub unsafe fn rebuild_from_model(tree: &mut QuadTree<&mut Entity, CoordT, DEPTH>, model: &mut [Entity]) -> usize {
+        //// SAFETY: The caller keeps to the contract, which is the same for both.
+        unsafe {QuadTree::rebuild_from_model_with_policy(tree, model, OutOfBounds::Reject)}
+    }
+
+    /// Rebuild a QuadTree from a model like rebuild_from_model, applying the policy to the entities outside the
+    /// tree. Clamped and wrapped entities are stored at the position they were moved to. A tree has no overflow
+    /// bucket, so Overflow leaves them out like Reject, see Bounded for a tree that keeps them.
+    /// Returns how many entities the policy was applied to.
+    ///
+    /// # Safety
+    /// The references put in the tree are not tied to the borrow of the model. Until the tree is cleared or
+    /// rebuilt, the model must not be moved, dropped or resized, and an entity must not be reached through the
+    /// tree while a reference to it taken from the model is alive, or the other way around.
+    pub unsafe fn rebuild_from_model_with_policy(tree: &mut QuadTree<&mut Entity, CoordT, DEPTH>, model: &mut [Entity], policy: OutOfBounds) -> usize {
+        tree.clear();
+        let mut outside = 0;
+        let bounds = tree.0.bounds();
+        for entity in model.iter_mut() {
+            let entity = entity as *mut Entity;
+            //// SAFETY: The tree is cleared before being filled, and the caller keeps the model in place.
             let entity = unsafe {&mut *entity};
-            //insert a reference to the entity into the tree
-            tree.0.insert(entity.get_x(), entity.get_y(), entity);
+            let (x, y) = (entity.get_x(), entity.get_y());
+            if !rect::contains(bounds, x, y) {
+                outside += 1;
+            }
+            //insert a reference to the entity into the tree, where the policy puts it
+            if let Some((x, y)) = policy.place(bounds, x, y) {
+                tree.0.insert(bounds, x, y, entity);
+            }
         }
+        outside
     }
 
-    /// Build a new QuadTree from scratch, and put references to the entities in it. The references are
-    /// anotated as static because this uses unsafe code to create them.
-    pub fn build_new_from_model(model: &mut Vec<Entity>, width: u16, height: u16) -> QuadTree<&'static mut Entity> {
-        let mut tree = QuadTree::new_empty(0, 0, width, height);
-        for i in 0..model.len() {
-            let entity = &mut model[i] as *mut Entity;
-            //// SAFETY: This is safe because the tree is new before being filled.
-            let entity =  unsafe{&mut *entity};
-            //insert a reference to the entity into the tree
-            tree.0.insert(entity.get_x(), entity.get_y(), entity);
+    /// Rebuild a QuadTree from a model on several threads, for models too large for rebuild_from_model.
+    /// The model is split into one chunk per thread, and each thread sorts its entities by the top level quadrant
+    /// they fall in. Then each of the 4 top level quadrants is filled on its own thread, taking the chunks in order,
+    /// so every leaf gets its entities in model order and the tree is the same as after rebuild_from_model.
+    /// This aliases the model through raw pointers like rebuild_from_model, and returns the same count.
+    ///
+    /// # Safety
+    /// The references put in the tree are not tied to the borrow of the model. Until the tree is cleared or
+    /// rebuilt, the model must not be moved, dropped or resized, and an entity must not be reached through the
+    /// tree while a reference to it taken from the model is alive, or the other way around.
+    pub unsafe fn rebuild_from_model_parallel(tree: &mut QuadTree<&mut Entity, CoordT, DEPTH>, model: &mut [Entity], threads: usize) -> usize where Entity: Send, CoordT: Send {
+        if threads <= 1 {
+            //// SAFETY: The caller keeps to the contract, which is the same for both.
+            return unsafe {QuadTree::rebuild_from_model(tree, model)};
+        }
+        tree.clear();
+        let bounds = tree.0.bounds();
+        let chunk_len = model.len().div_ceil(threads).max(1);
+        let chunks: Vec<([Vec<&mut Entity>; 4], usize)> = thread::scope(|scope| {
+            let workers: Vec<_> = model.chunks_mut(chunk_len).map(|chunk| scope.spawn(move || {
+                let mut bins: [Vec<&mut Entity>; 4] = Default::default();
+                let mut outside = 0;
+                for entity in chunk {
+                    let entity = entity as *mut Entity;
+                    //// SAFETY: The tree is cleared before being filled, and the caller keeps the model in place.
+                    let entity = unsafe {&mut *entity};
+                    if rect::contains(bounds, entity.get_x(), entity.get_y()) {
+                        bins[quadrant_of(bounds, entity.get_x(), entity.get_y())].push(entity);
+                    } else {
+                        outside += 1;
+                    }
+                }
+                (bins, outside)
+            })).collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+        let mut bins: [Vec<Vec<&mut Entity>>; 4] = Default::default();
+        let mut outside = 0;
+        for (chunk, chunk_outside) in chunks {
+            outside += chunk_outside;
+            for (bin, quadrant) in chunk.into_iter().zip(bins.iter_mut()) {
+                quadrant.push(bin);
+            }
+        }
+        let mut leaves = Vec::with_capacity(1 << (2 * DEPTH));
+        tree.0.for_each_leaf_mut(&mut |leaf| leaves.push(leaf));
+        let quarter = leaves.len() / 4;
+        let rects = divide_into_4(bounds.0, bounds.1, bounds.2, bounds.3);
+        thread::scope(|scope| {
+            for ((leaves, quad_rect), bins) in leaves.chunks_mut(quarter).zip(rects).zip(bins) {
+                scope.spawn(move || {
+                    for entity in bins.into_iter().flatten() {
+                        insert_into_leaves(leaves, quad_rect, entity.get_x(), entity.get_y(), entity);
+                    }
+                });
+            }
+        });
+        outside
+    }
+
+    /// Build a new QuadTree of references to the entities. The tree borrows the model, so the model can not
+    /// be dropped or changed while the tree is alive.
+    pub fn build_from_model(model: &mut [Entity], rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT) -> QuadTree<&mut Entity, CoordT, DEPTH> {
+        QuadTree::refill_from_model(QuadTree::new_empty(rect_x, rect_y, rect_w, rect_h), model)
+    }
+
+    /// Fill an old tree with references to the entities of a new borrow of the model. The old tree is recycled,
+    /// so the capacities of its buckets are kept like in rebuild_from_model.
+    pub fn refill_from_model<'a>(tree: QuadTree<&mut Entity, CoordT, DEPTH>, model: &'a mut [Entity]) -> QuadTree<&'a mut Entity, CoordT, DEPTH> {
+        let mut tree = tree.recycle();
+        for entity in model.iter_mut() {
+            tree.insert(entity.get_x(), entity.get_y(), entity);
         }
         tree
     }
 
+    /// Fill the tree with references to the model, and call f with it. The tree is emptied again when f returns,
+    /// so it can be kept from frame to frame next to the model without borrowing it. If f panics, the tree is
+    /// left empty and covering no area.
+    pub fn with_tree<'a, R>(tree: &mut QuadTree<&mut Entity, CoordT, DEPTH>, model: &'a mut [Entity], f: impl FnOnce(&mut QuadTree<&'a mut Entity, CoordT, DEPTH>) -> R) -> R {
+        let empty = QuadTree::new_empty(CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, CoordT::ZERO);
+        let mut filled = QuadTree::refill_from_model(std::mem::replace(tree, empty), model);
+        let result = f(&mut filled);
+        *tree = filled.recycle();
+        result
+    }
+
+    /// Fill a tree with the indices of the entities of the model, at their positions. A tree of indices does
+    /// not borrow the model, so it can be kept next to it and updated with refresh_from_model as entities move.
+    /// Returns how many entities were left out because they are outside the tree.
+    pub fn index_model(tree: &mut QuadTree<usize, CoordT, DEPTH>, model: &[Entity]) -> usize {
+        tree.clear();
+        model.iter().enumerate().filter(|&(index, entity)| !tree.insert(entity.get_x(), entity.get_y(), index)).count()
+    }
+
+    /// Move the index of an entity in a tree of indices after the entity moved away from (old_x, old_y).
+    /// The new position is read from the model, and other entities at the same position are left alone.
+    /// Returns false like relocate.
+    pub fn update_position(tree: &mut QuadTree<usize, CoordT, DEPTH>, model: &[Entity], index: usize, old_x: CoordT, old_y: CoordT) -> bool {
+        let entity = &model[index];
+        tree.relocate(old_x, old_y, entity.get_x(), entity.get_y(), |&other| other == index)
+    }
+
+    /// Update a tree of indices filled with index_model after some of the entities moved, instead of refilling it.
+    /// dirty holds the index into the model and the old position of every entity that moved since the last
+    /// refresh, and only those entities are touched. Returns how many of them could not be moved.
+    pub fn refresh_from_model(tree: &mut QuadTree<usize, CoordT, DEPTH>, model: &[Entity], dirty: &[(usize, CoordT, CoordT)]) -> usize {
+        dirty.iter().filter(|&&(index, old_x, old_y)| !QuadTree::update_position(tree, model, index, old_x, old_y)).count()
+    }
+
 
 }
 
-impl<Entity: GetX+GetY+Clone> QuadTree<Entity> {
+impl<Entity: GetX<CoordT>+GetY<CoordT>+GetWidth<CoordT>+GetHeight<CoordT>, CoordT: Coord, const DEPTH: usize> QuadTree<Entity, CoordT, DEPTH> where Depth<DEPTH>: Levels {
+
+    /// Fill an old tree with references to the entities of a new borrow of the model, like refill_from_model, but
+    /// with the box of every entity. Entities whose box crosses a leaf border go to the straddling bucket, so
+    /// for_each_pair_mut, or par_for_each_leaf_mut followed by for_each_straddling_pair_mut, finds every
+    /// overlapping pair.
+    pub fn refill_boxes_from_model<'a>(tree: QuadTree<&mut Entity, CoordT, DEPTH>, model: &'a mut [Entity]) -> QuadTree<&'a mut Entity, CoordT, DEPTH> {
+        let mut tree = tree.recycle();
+        for entity in model.iter_mut() {
+            tree.insert_box(entity.get_x(), entity.get_y(), entity.get_width(), entity.get_height(), entity);
+        }
+        tree
+    }
+
+    /// Like with_tree, with the tree filled by refill_boxes_from_model
+    pub fn with_tree_boxes<'a, R>(tree: &mut QuadTree<&mut Entity, CoordT, DEPTH>, model: &'a mut [Entity], f: impl FnOnce(&mut QuadTree<&'a mut Entity, CoordT, DEPTH>) -> R) -> R {
+        let empty = QuadTree::new_empty(CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, CoordT::ZERO);
+        let mut filled = QuadTree::refill_boxes_from_model(std::mem::replace(tree, empty), model);
+        let result = f(&mut filled);
+        *tree = filled.recycle();
+        result
+    }
+}
+
+impl<Entity: GetX<CoordT>+GetY<CoordT>+Clone, CoordT: Coord, const DEPTH: usize> QuadTree<Entity, CoordT, DEPTH> where Depth<DEPTH>: Levels {
 
     /// A version that returns a QuadTree that owns clones of the entities
-    pub fn build_owned_from_model(model: &mut Vec<Entity>) -> QuadTree<Entity> {
-        let mut tree = QuadTree::new_empty(0, 0, 1000, 1000);
-        for i in 0..model.len() {
-            let entity = model[i].clone();
+    pub fn build_owned_from_model(model: &mut [Entity], rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT) -> QuadTree<Entity, CoordT, DEPTH> {
+        let mut tree = QuadTree::new_empty(rect_x, rect_y, rect_w, rect_h);
+        let bounds = tree.0.bounds();
+        for entity in model.iter() {
+            let entity = entity.clone();
             //insert a reference to the entity into the tree
-            tree.0.insert(entity.get_x(), entity.get_y(), entity);
+            tree.0.insert(bounds, entity.get_x(), entity.get_y(), entity);
         }
         tree
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::{init_model, Entity, ModelConfig};
+
+    /// The addresses of the entities in every leaf, in order
+    fn leaf_addresses(tree: &mut QuadTree<&mut Entity>) -> Vec<Vec<usize>> {
+        let mut leaves = Vec::new();
+        tree.0.for_each_leaf_mut(&mut |leaf| leaves.push(leaf.data.iter().map(|entity| &**entity as *const Entity as usize).collect()));
+        leaves
+    }
+
+    #[test]
+    fn parallel_rebuild_matches_sequential() {
+        //an empty model, more threads than entities, one thread, and chunks that do not divide the model evenly
+        for (model_size, threads) in [(0, 4), (3, 8), (1000, 1), (1000, 3), (1000, 16)] {
+            //part of the world is outside the tree, so the count of entities left out is checked too
+            let mut model = init_model(ModelConfig { model_size, world_size: 140, ..Default::default() });
+            let outside = model.iter().filter(|entity| entity.x >= 128 || entity.y >= 128).count();
+            let mut tree: QuadTree<&mut Entity> = QuadTree::new_empty(0, 0, 128, 128);
+            let expected = QuadTree::with_tree(&mut tree, &mut model, |tree| leaf_addresses(tree));
+            //// SAFETY: The model is not touched while the tree is alive.
+            assert_eq!(unsafe {QuadTree::rebuild_from_model_parallel(&mut tree, &mut model, threads)}, outside);
+            assert_eq!(leaf_addresses(&mut tree), expected);
+        }
+    }
+
+    #[test]
+    fn relocate_moves_items_within_and_across_leaves() {
+        let mut tree: QuadTree<usize> = QuadTree::new_empty(0, 0, 128, 128);
+        assert!(tree.insert(1, 1, 0) && tree.insert(1, 1, 1));
+        //inside the same 16x16 leaf, the other item at the same position stays
+        assert!(tree.relocate(1, 1, 15, 15, |&i| i == 0));
+        assert_eq!(tree.query_rect(1, 1, 1, 1), vec![&1]);
+        assert_eq!(tree.query_rect(15, 15, 1, 1), vec![&0]);
+        assert_eq!(tree.broad_phase(1, 1).len(), 2);
+        //into another leaf
+        assert!(tree.relocate(15, 15, 16, 100, |&i| i == 0));
+        assert_eq!(tree.broad_phase(1, 1), &vec![1]);
+        assert_eq!(tree.broad_phase(16, 100), &vec![0]);
+        //outside the tree, or from a position where the item is not, nothing moves
+        assert!(!tree.relocate(16, 100, 128, 5, |&i| i == 0));
+        assert!(!tree.relocate(1, 1, 5, 5, |&i| i == 0));
+        assert_eq!(tree.query_rect(16, 100, 1, 1), vec![&0]);
+        assert_eq!(tree.query_rect(0, 0, 128, 128).len(), 2);
+    }
+
+    #[test]
+    fn refresh_from_model_follows_the_moved_entities() {
+        let mut model = init_model(ModelConfig { model_size: 200, ..Default::default() });
+        let mut tree: QuadTree<usize> = QuadTree::new_empty(0, 0, 128, 128);
+        assert_eq!(QuadTree::index_model(&mut tree, &model), 0);
+        let mut dirty = Vec::new();
+        for (i, entity) in model.iter_mut().enumerate().step_by(3) {
+            dirty.push((i, entity.x, entity.y));
+            //every 30th entity walks off the world and keeps its old place in the tree
+            (entity.x, entity.y) = if i % 30 == 0 { (entity.x + 128, entity.y) } else { ((entity.x + 37) % 128, (entity.y * 3) % 128) };
+        }
+        assert_eq!(QuadTree::refresh_from_model(&mut tree, &model, &dirty), 7);
+        for (i, entity) in model.iter().enumerate() {
+            let (x, y) = if i % 30 == 0 { (entity.x - 128, entity.y) } else { (entity.x, entity.y) };
+            assert!(tree.query_rect(x, y, 1, 1).contains(&&i), "{} at {:?}", i, (x, y));
+        }
+        assert_eq!(tree.query_rect(0, 0, 128, 128).len(), 200);
+    }
+
+    /// Build a tree of the depth over a 100x100 world, fill it with a point at every unit and check that every
+    /// leaf holds its share and that the queries find them
+    fn check_depth<const DEPTH: usize>() where Depth<DEPTH>: Levels {
+        let mut tree: QuadTree<(u16, u16), u16, DEPTH> = QuadTree::new_empty(0, 0, 100, 100);
+        assert_eq!(QuadTree::<(u16, u16), u16, DEPTH>::DEPTH, DEPTH);
+        assert_eq!(QuadTree::<(u16, u16), u16, DEPTH>::LEAVES_PER_SIDE, 1 << DEPTH);
+        let leaf_size = QuadTree::<(u16, u16), u16, DEPTH>::leaf_size(100) as u16;
+        assert_eq!(leaf_size, 100 >> DEPTH);
+        for x in 0..100 {
+            for y in 0..100 {
+                assert!(tree.insert(x, y, (x, y)));
+            }
+        }
+        let mut leaves = 0;
+        tree.0.for_each_leaf_mut(&mut |leaf| {
+            leaves += 1;
+            //the top left leaf is leaf_size wide, the others can be one unit wider
+            let (w, h) = (leaf.rect_w as usize, leaf.rect_h as usize);
+            assert!(w >= leaf_size as usize && w <= leaf_size as usize + 1 && h >= leaf_size as usize && h <= leaf_size as usize + 1);
+            assert_eq!(leaf.data.len(), w * h);
+        });
+        assert_eq!(leaves, (1 << DEPTH) * (1 << DEPTH));
+        assert_eq!(tree.broad_phase(0, 0).len(), (leaf_size * leaf_size) as usize);
+        assert!(tree.broad_phase(99, 99).contains(&(99, 99)));
+        assert_eq!(tree.query_rect(10, 20, 30, 40).len(), 30 * 40);
+        assert!(!tree.insert(100, 0, (100, 0)));
+    }
+
+    #[test]
+    fn every_depth_builds_fills_and_queries() {
+        check_depth::<1>();
+        check_depth::<2>();
+        check_depth::<3>();
+        check_depth::<4>();
+        check_depth::<5>();
+        check_depth::<6>();
+    }
+
+    #[test]
+    fn parallel_leaves_and_straddling_pairs_match_sequential_pairs() {
+        use crate::entity::{is_coliding, update_entity_pair, update_leaf_local};
+        use std::sync::Mutex;
+        //every leaf is handed out once, with more threads than leaves and with runs that do not divide the leaves evenly
+        let mut tree: QuadTree<usize> = QuadTree::new_empty(0, 0, 128, 128);
+        for i in 0..1000 {
+            assert!(tree.insert((i * 37 % 128) as u16, (i * 91 % 128) as u16, i));
+        }
+        let mut expected = Vec::new();
+        tree.0.for_each_leaf_mut(&mut |leaf| expected.push(leaf.data.clone()));
+        expected.sort();
+        for threads in [0, 1, 3, 64, 100] {
+            let visited = Mutex::new(Vec::new());
+            tree.par_for_each_leaf_mut(threads, |leaf| visited.lock().unwrap().push(leaf.to_vec()));
+            let mut visited = visited.into_inner().unwrap();
+            visited.sort();
+            assert_eq!(visited, expected, "{} threads", threads);
+        }
+
+        //the entities are boxes, so some of them straddle the leaf borders
+        let model = init_model(ModelConfig { model_size: 1000, world_size: 124, entity_size: 4, ..Default::default() });
+        let collisions = |model: &[Entity]| model.iter().map(|entity| entity.collision).collect::<Vec<_>>();
+        let brute_force: Vec<bool> = model.iter().enumerate().map(|(i, entity)| {
+            model.iter().enumerate().any(|(j, other_entity)| i != j && is_coliding(entity, other_entity))
+        }).collect();
+        let mut sequential = model.clone();
+        let mut tree: QuadTree<&mut Entity> = QuadTree::new_empty(0, 0, 128, 128);
+        QuadTree::with_tree_boxes(&mut tree, &mut sequential, |tree| tree.for_each_pair_mut(|entity, other_entity| update_entity_pair(entity, other_entity)));
+        assert_eq!(collisions(&sequential), brute_force);
+        for threads in [1, 3, 16] {
+            let mut parallel = model.clone();
+            QuadTree::with_tree_boxes(&mut tree, &mut parallel, |tree| {
+                tree.par_for_each_leaf_mut(threads, update_leaf_local);
+                tree.for_each_straddling_pair_mut(|entity, other_entity| update_entity_pair(entity, other_entity));
+            });
+            assert_eq!(collisions(&parallel), brute_force, "{} threads", threads);
+        }
+
+        //the entity at x=14 reaches over the border of the 16x16 leaves into the one at x=16, only the straddling pass finds them
+        let entity = |x, y| Entity { x, y, width: 4, height: 4, collision: false };
+        let mut model = vec![entity(14, 20), entity(16, 20)];
+        QuadTree::with_tree_boxes(&mut tree, &mut model, |tree| tree.par_for_each_leaf_mut(2, update_leaf_local));
+        assert_eq!(collisions(&model), [false, false]);
+        QuadTree::with_tree_boxes(&mut tree, &mut model, |tree| tree.for_each_straddling_pair_mut(|entity, other_entity| update_entity_pair(entity, other_entity)));
+        assert_eq!(collisions(&model), [true, true]);
+    }
+
+    #[test]
+    fn remove_takes_items_from_leaves_and_the_straddling_bucket() {
+        let mut tree: QuadTree<usize> = QuadTree::new_empty(0, 0, 128, 128);
+        assert!(tree.insert(5, 5, 0) && tree.insert(5, 5, 1) && tree.insert(6, 5, 2));
+        //crosses the border between the 16x16 leaves at x=64, so it is kept in the straddling bucket
+        assert!(tree.insert_box(60, 10, 8, 4, 3) && tree.insert_box(60, 10, 2, 2, 4));
+        assert_eq!(tree.1.data, vec![3]);
+        //only the items at exactly the point for which the predicate is true
+        assert_eq!(tree.remove(5, 5, |&i| i == 1), vec![1]);
+        assert_eq!(tree.broad_phase(5, 5), &vec![0, 2]);
+        assert_eq!(tree.remove(5, 5, |&i| i == 2), Vec::<usize>::new());
+        //from the straddling bucket, leaving the item at the same point in its leaf
+        assert_eq!(tree.remove(60, 10, |&i| i == 3), vec![3]);
+        assert!(tree.1.data.is_empty());
+        assert_eq!(tree.query_rect(60, 10, 1, 1), vec![&4]);
+        //both at once, and nothing outside the tree
+        assert!(tree.insert_box(60, 10, 8, 4, 3));
+        let mut removed = tree.remove(60, 10, |_| true);
+        removed.sort();
+        assert_eq!(removed, vec![3, 4]);
+        assert!(tree.remove(128, 5, |_| true).is_empty());
+        assert_eq!(tree.query_rect(0, 0, 128, 128).len(), 2);
+    }
+
+    #[test]
+    fn remove_where_matches_a_filter() {
+        let mut tree: QuadTree<usize> = QuadTree::new_empty(0, 0, 128, 128);
+        let items: Vec<(u16, u16, u16, u16)> = (0..300).map(|i| ((i * 37 % 128) as u16, (i * 91 % 128) as u16, (i % 5) as u16, (i % 7) as u16)).collect();
+        for (i, &(x, y, w, h)) in items.iter().enumerate() {
+            assert!(tree.insert_box(x, y, w, h, i));
+        }
+        assert!(!tree.1.data.is_empty());
+        let filter = |i: usize, x: u16, y: u16| i % 3 == 1 || x < y;
+        let mut removed = tree.remove_where(|&i, x, y| {
+            //the stored position is handed to the predicate
+            assert_eq!((x, y), (items[i].0, items[i].1));
+            filter(i, x, y)
+        });
+        removed.sort();
+        let expected: Vec<usize> = (0..items.len()).filter(|&i| filter(i, items[i].0, items[i].1)).collect();
+        assert_eq!(removed, expected);
+        let mut left: Vec<usize> = tree.query_rect(0, 0, 128, 128).into_iter().copied().collect();
+        left.sort();
+        assert_eq!(left, (0..items.len()).filter(|&i| !filter(i, items[i].0, items[i].1)).collect::<Vec<_>>());
+        assert!(tree.remove_where(|&i, x, y| filter(i, x, y)).is_empty());
+    }
+}
@@ -2,48 +2,61 @@
 
 pub mod fixed_depth_quad_tree;
 pub mod enum_quadtree;
+pub mod loose_quadtree;
+pub mod morton_quadtree;
+pub mod uniform_grid;
 pub mod entity;
+pub mod bucket;
+pub mod builder;
+pub mod coord;
+pub mod spatial_index;
+pub mod error;
+pub mod bounded;
+pub mod verify;
+mod rect;
+mod nearest;
 use entity::*;
 
-pub trait GetX {fn get_x(&self) -> u16;}
-pub trait GetY {fn get_y(&self) -> u16;}
-
-pub fn example_main() {
-    
-    use fixed_depth_quad_tree::*;
+pub trait GetX<CoordT = u16> {fn get_x(&self) -> CoordT;}
+pub trait GetY<CoordT = u16> {fn get_y(&self) -> CoordT;}
+pub trait GetWidth<CoordT = u16> {fn get_width(&self) -> CoordT;}
+pub trait GetHeight<CoordT = u16> {fn get_height(&self) -> CoordT;}
+
+//References to items have the same position and size as the items, so trees of references can be built from them
+impl<T: GetX<CoordT> + ?Sized, CoordT> GetX<CoordT> for &T {fn get_x(&self) -> CoordT {(**self).get_x()}}
+impl<T: GetY<CoordT> + ?Sized, CoordT> GetY<CoordT> for &T {fn get_y(&self) -> CoordT {(**self).get_y()}}
+impl<T: GetWidth<CoordT> + ?Sized, CoordT> GetWidth<CoordT> for &T {fn get_width(&self) -> CoordT {(**self).get_width()}}
+impl<T: GetHeight<CoordT> + ?Sized, CoordT> GetHeight<CoordT> for &T {fn get_height(&self) -> CoordT {(**self).get_height()}}
+impl<T: GetX<CoordT> + ?Sized, CoordT> GetX<CoordT> for &mut T {fn get_x(&self) -> CoordT {(**self).get_x()}}
+impl<T: GetY<CoordT> + ?Sized, CoordT> GetY<CoordT> for &mut T {fn get_y(&self) -> CoordT {(**self).get_y()}}
+impl<T: GetWidth<CoordT> + ?Sized, CoordT> GetWidth<CoordT> for &mut T {fn get_width(&self) -> CoordT {(**self).get_width()}}
+impl<T: GetHeight<CoordT> + ?Sized, CoordT> GetHeight<CoordT> for &mut T {fn get_height(&self) -> CoordT {(**self).get_height()}}
+
+/// The main game loop, written once for any backend. The index holds the positions of the entities in the
+/// model, so it borrows nothing and the entities are updated through the model.
+pub fn example_loop<Index: spatial_index::SpatialIndex<usize>>() {
 
     let mut model = init_model(Default::default());
-    let mut tree = QuadTree::new_empty(0,0,128,128); //128x128 world, 8x8 grid, so every leaf is 16x16
+    let mut tree = Index::new(0,0,128,128); //128x128 world, 8x8 grid, so every leaf is 16x16
 
     //the main game loop
     loop {
 
-        QuadTree::rebuild_from_model(&mut tree, &mut model);
+        spatial_index::index_model(&mut tree, &model);
 
         //update the entities
-        for entity in model.iter_mut() {
-            let local_model = tree.broad_phase_mut(entity.x, entity.y);
-            update_entity_local(entity, local_model);
+        for i in 0..model.len() {
+            let (x, y, w, h) = neighbourhood(&model[i]);
+            let candidates = tree.query_rect(x, y, w, h);
+            update_entity_indexed(&mut model, i, candidates.into_iter().copied());
         }
     }
 }
 
-pub fn example_main_2() {
-    
-    use enum_quadtree::*;
-
-    let mut model = init_model(Default::default());
-    let mut tree = QuadTree::new_empty(0,0,128,128, 3); //128x128 world, 8x8 grid, so every leaf is 16x16
-
-    //the main game loop
-    loop {
-
-        QuadTree::rebuild_from_model(&mut tree, &mut model);
+pub fn example_main() {
+    example_loop::<fixed_depth_quad_tree::QuadTree<_>>()
+}
 
-        //update the entities
-        for entity in model.iter_mut() {
-            let local_model = tree.broad_phase_mut(entity.x, entity.y);
-            update_entity_local(entity, local_model);
-        }
-    }
-}
\ No newline at end of file
+pub fn example_main_2() {
+    example_loop::<enum_quadtree::QuadTree<_>>()
+}
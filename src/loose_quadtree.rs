@@ -0,0 +1,440 @@
+use super::rect::{self, divide_into_4, quadrant_of, Rect};
+use super::bucket::{recycle_vec, take_where};
+use super::coord::Coord;
+use super::builder::{FromBuilder, QuadTreeBuilder};
+use super::error::{check_bounds, QuadTreeError};
+use super::spatial_index::SpatialIndex;
+
+/// A loose QuadTree. Every node covers a rect like in the other trees, but it accepts items whose box fits in its
+/// loose bounds, which are the rect grown by half its size on every side (so twice as wide and twice as high).
+/// An item is stored once, at the deepest node whose loose bounds contain its whole box, so items of any size
+/// are indexed without being duplicated or kept in a straddling bucket.
+pub struct QuadTree<DataT, CoordT: Coord = u16> {
+    //Items stored at this node
+    pub data: Vec<DataT>,
+    //The top left corner of each item's box
+    positions: Vec<(CoordT, CoordT)>,
+    //Width and height of each item's box, items inserted as a point have a size of 0
+    sizes: Vec<(CoordT, CoordT)>,
+    //The (tight) bounding box of the node
+    rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT,
+    //None for the bottom level of the tree
+    quads: Option<Box<[QuadTree<DataT, CoordT>; 4]>>,
+}
+
+///The loose bounds of a node as (x, y, far x, far y). These can reach past the ends of the coordinate range, so f64 is used.
+fn loose<CoordT: Coord>(node_rect: Rect<CoordT>) -> (f64, f64, f64, f64) {
+    let (x, y, w, h) = (node_rect.0.to_f64(), node_rect.1.to_f64(), node_rect.2.to_f64(), node_rect.3.to_f64());
+    let (half_w, half_h) = (node_rect.2.half().to_f64(), node_rect.3.half().to_f64());
+    (x - half_w, y - half_h, x + w + half_w, y + h + half_h)
+}
+
+///Return true if the whole item box is inside the loose bounds of the node
+fn fits_loose<CoordT: Coord>(item: Rect<CoordT>, node_rect: Rect<CoordT>) -> bool {
+    let (x, y, far_x, far_y) = loose(node_rect);
+    item.0.to_f64() >= x && item.1.to_f64() >= y &&
+    item.0.to_f64() + item.2.to_f64() <= far_x && item.1.to_f64() + item.3.to_f64() <= far_y
+}
+
+///The index of the quadrant of divide_into_4 that owns the center of the item box, compared with the midlines
+///of the tight rect like quadrant_of. The center is measured in f64, so a large box near the end of the coordinate
+///range does not overflow.
+fn quadrant_of_center<CoordT: Coord>(node_rect: Rect<CoordT>, item: Rect<CoordT>) -> usize {
+    let mid_x = (node_rect.0 + node_rect.2.half()).to_f64();
+    let mid_y = (node_rect.1 + node_rect.3.half()).to_f64();
+    let center_x = item.0.to_f64() + item.2.half().to_f64();
+    let center_y = item.1.to_f64() + item.3.half().to_f64();
+    (center_x >= mid_x) as usize + 2 * (center_y >= mid_y) as usize
+}
+
+///Return true if the query rect overlaps the loose bounds of the node. Items are at most as large as the loose
+///bounds, so a query that touches an item touches the loose bounds, even a zero sized query on the far edge.
+fn overlaps_loose<CoordT: Coord>(query: Rect<CoordT>, node_rect: Rect<CoordT>) -> bool {
+    let (x, y, far_x, far_y) = loose(node_rect);
+    query.0.to_f64() <= far_x && query.0.to_f64() + query.2.to_f64() >= x &&
+    query.1.to_f64() <= far_y && query.1.to_f64() + query.3.to_f64() >= y
+}
+
+impl<DataT, CoordT: Coord> QuadTree<DataT, CoordT> {
+    /// Construct a node with 4 empty quadrants, each containing other quadrants, down to the given depth
+    pub fn new_empty(rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT, depth: u16) -> Self {
+        let quads = if depth == 0 {
+            None
+        } else {
+            let rect = divide_into_4(rect_x, rect_y, rect_w, rect_h);
+            Some(Box::new([
+                QuadTree::new_empty(rect[0].0, rect[0].1, rect[0].2, rect[0].3, depth - 1),
+                QuadTree::new_empty(rect[1].0, rect[1].1, rect[1].2, rect[1].3, depth - 1),
+                QuadTree::new_empty(rect[2].0, rect[2].1, rect[2].2, rect[2].3, depth - 1),
+                QuadTree::new_empty(rect[3].0, rect[3].1, rect[3].2, rect[3].3, depth - 1),
+            ]))
+        };
+        QuadTree { data: Vec::new(), positions: Vec::new(), sizes: Vec::new(), rect_x, rect_y, rect_w, rect_h, quads }
+    }
+    fn rect(&self) -> Rect<CoordT> {
+        (self.rect_x, self.rect_y, self.rect_w, self.rect_h)
+    }
+    /// Remove all items from all nodes
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.positions.clear();
+        self.sizes.clear();
+        if let Some(quads) = &mut self.quads {
+            for quad in quads.iter_mut() {
+                quad.clear();
+            }
+        }
+    }
+    /// Empty the tree and give it a new item type, keeping its shape and the capacity of its buckets.
+    /// This lets a tree of references into a model be filled again for the next borrow of the model.
+    pub fn recycle<OtherT>(mut self) -> QuadTree<OtherT, CoordT> {
+        self.positions.clear();
+        self.sizes.clear();
+        let QuadTree { data, positions, sizes, rect_x, rect_y, rect_w, rect_h, quads } = self;
+        let quads = quads.map(|quads| Box::new(quads.map(QuadTree::recycle)));
+        QuadTree { data: recycle_vec(data), positions, sizes, rect_x, rect_y, rect_w, rect_h, quads }
+    }
+    /// Reserve room for additional items in every node of the bottom level
+    fn reserve_leaves(&mut self, additional: usize) {
+        match &mut self.quads {
+            Some(quads) => {
+                for quad in quads.iter_mut() {
+                    quad.reserve_leaves(additional);
+                }
+            }
+            None => {
+                self.data.reserve(additional);
+                self.positions.reserve(additional);
+                self.sizes.reserve(additional);
+            }
+        }
+    }
+    /// Insert a point, or return false if it is outside the tree
+    pub fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.insert_box(x, y, CoordT::ZERO, CoordT::ZERO, data)
+    }
+    /// Insert a point. If it is outside the tree, the data is given back with the error, so the caller can keep
+    /// it or store it elsewhere.
+    pub fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        self.try_insert_box(x, y, CoordT::ZERO, CoordT::ZERO, data)
+    }
+    /// Insert an item with a bounding box, or return false if its top left corner is outside the tree.
+    /// The item is stored at the deepest node whose loose bounds contain the whole box. A box that does not
+    /// fit in the loose bounds of the root is kept at the root.
+    pub fn insert_box(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT, data: DataT) -> bool {
+        self.try_insert_box(x, y, w, h, data).is_ok()
+    }
+    /// Insert an item with a bounding box like insert_box. If the top left corner is outside the tree, the data
+    /// is given back with the error.
+    pub fn try_insert_box(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        if let Err(error) = check_bounds(self.rect(), x, y) {
+            return Err((data, error));
+        }
+        self.insert_sized((x, y, w, h), data);
+        Ok(())
+    }
+    fn insert_sized(&mut self, item: Rect<CoordT>, data: DataT) {
+        let node_rect = self.rect();
+        if let Some(quads) = &mut self.quads {
+            //the quadrant that holds the center of the box is the only one whose loose bounds can contain it
+            let quad = &mut quads[quadrant_of_center(node_rect, item)];
+            if fits_loose(item, quad.rect()) {
+                return quad.insert_sized(item, data);
+            }
+        }
+        self.data.push(data);
+        self.positions.push((item.0, item.1));
+        self.sizes.push((item.2, item.3));
+    }
+    /// Call f with the data, positions and sizes of every node whose loose bounds overlap the query.
+    /// The root is always visited, because boxes that do not fit in its loose bounds are kept there.
+    fn for_each_node_overlapping<'a>(&'a self, query: Rect<CoordT>, is_root: bool, f: &mut impl FnMut(&'a [DataT], &'a [(CoordT, CoordT)], &'a [(CoordT, CoordT)])) {
+        if !is_root && !overlaps_loose(query, self.rect()) {
+            return;
+        }
+        f(&self.data, &self.positions, &self.sizes);
+        if let Some(quads) = &self.quads {
+            for quad in quads.iter() {
+                quad.for_each_node_overlapping(query, false, f);
+            }
+        }
+    }
+    /// Mutable version of for_each_node_overlapping
+    fn for_each_node_overlapping_mut<'a>(&'a mut self, query: Rect<CoordT>, is_root: bool, f: &mut impl FnMut(&'a mut [DataT], &'a [(CoordT, CoordT)], &'a [(CoordT, CoordT)])) {
+        if !is_root && !overlaps_loose(query, self.rect()) {
+            return;
+        }
+        f(&mut self.data, &self.positions, &self.sizes);
+        if let Some(quads) = &mut self.quads {
+            for quad in quads.iter_mut() {
+                quad.for_each_node_overlapping_mut(query, false, f);
+            }
+        }
+    }
+    /// Return every item whose box overlaps the query box. Items inserted as a point have a size of 0.
+    /// Every item is stored once, so no item is returned twice.
+    pub fn query_box(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_node_overlapping(query, true, &mut |data, positions, sizes| {
+            for ((item, &(item_x, item_y)), &(item_w, item_h)) in data.iter().zip(positions).zip(sizes) {
+                if rect::overlaps(query, (item_x, item_y, item_w, item_h)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Mutable version of query_box
+    pub fn query_box_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_node_overlapping_mut(query, true, &mut |data, positions, sizes| {
+            for ((item, &(item_x, item_y)), &(item_w, item_h)) in data.iter_mut().zip(positions).zip(sizes) {
+                if rect::overlaps(query, (item_x, item_y, item_w, item_h)) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// The node of the bottom level whose rect contains the point, descending from this node in O(depth).
+    /// The point must be inside the tree.
+    fn leaf_mut(&mut self, x: CoordT, y: CoordT) -> &mut Self {
+        if self.quads.is_none() {
+            return self;
+        }
+        let index = quadrant_of(self.rect(), x, y);
+        self.quads.as_mut().unwrap()[index].leaf_mut(x, y)
+    }
+    /// Immutable version of leaf_mut
+    fn leaf(&self, x: CoordT, y: CoordT) -> &Self {
+        match &self.quads {
+            Some(quads) => quads[quadrant_of(self.rect(), x, y)].leaf(x, y),
+            None => self,
+        }
+    }
+    /// Return a reference to the vec of data of the node of the bottom level that contains the point. Points
+    /// always descend to the bottom level, so these are the items inserted as a point near it, like the leaf of
+    /// the other trees. Items with a box can be kept higher up, use query_box to find those.
+    /// Panics if the point is outside the tree, see try_broad_phase.
+    pub fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.try_broad_phase(x, y).unwrap()
+    }
+    /// Mutable version of broad_phase. Panics if the point is outside the tree, see try_broad_phase_mut.
+    pub fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.try_broad_phase_mut(x, y).unwrap()
+    }
+    /// Return the items of broad_phase, or OutOfBounds if the point is outside the tree
+    pub fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        check_bounds(self.rect(), x, y)?;
+        Ok(&self.leaf(x, y).data)
+    }
+    /// Mutable version of try_broad_phase
+    pub fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        check_bounds(self.rect(), x, y)?;
+        Ok(&mut self.leaf_mut(x, y).data)
+    }
+    /// Return every item whose top left corner is inside the rect
+    pub fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_node_overlapping(query, true, &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter().zip(positions) {
+                if rect::contains(query, item_x, item_y) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Mutable version of query_rect
+    pub fn query_rect_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_node_overlapping_mut(query, true, &mut |data, positions, _| {
+            for (item, &(item_x, item_y)) in data.iter_mut().zip(positions) {
+                if rect::contains(query, item_x, item_y) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Remove every item in the tree for which the predicate returns true, and return them.
+    /// The predicate is given the item and the top left corner of its box.
+    pub fn remove_where(&mut self, mut predicate: impl FnMut(&DataT, CoordT, CoordT) -> bool) -> Vec<DataT> {
+        let mut removed = Vec::new();
+        self.remove_where_into(&mut predicate, &mut removed);
+        removed
+    }
+    /// Remove the items whose box has its top left corner at exactly (x, y) and for which the predicate returns
+    /// true, and return them. Only the nodes whose loose bounds contain the point are searched.
+    pub fn remove(&mut self, x: CoordT, y: CoordT, mut predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        let mut removed = Vec::new();
+        self.remove_at_into((x, y), true, &mut |item, item_x, item_y| (item_x, item_y) == (x, y) && predicate(item), &mut removed);
+        removed
+    }
+    fn remove_at_into(&mut self, (x, y): (CoordT, CoordT), is_root: bool, predicate: &mut impl FnMut(&DataT, CoordT, CoordT) -> bool, removed: &mut Vec<DataT>) {
+        if !is_root && !overlaps_loose((x, y, CoordT::ZERO, CoordT::ZERO), self.rect()) {
+            return;
+        }
+        removed.append(&mut take_where(&mut self.data, &mut self.positions, &mut self.sizes, predicate));
+        if let Some(quads) = &mut self.quads {
+            for quad in quads.iter_mut() {
+                quad.remove_at_into((x, y), false, predicate, removed);
+            }
+        }
+    }
+    fn remove_where_into(&mut self, predicate: &mut impl FnMut(&DataT, CoordT, CoordT) -> bool, removed: &mut Vec<DataT>) {
+        removed.append(&mut take_where(&mut self.data, &mut self.positions, &mut self.sizes, predicate));
+        if let Some(quads) = &mut self.quads {
+            for quad in quads.iter_mut() {
+                quad.remove_where_into(predicate, removed);
+            }
+        }
+    }
+}
+
+impl<DataT, CoordT: Coord> FromBuilder<DataT, CoordT> for QuadTree<DataT, CoordT> {
+    fn from_builder(builder: &QuadTreeBuilder<CoordT>) -> Self {
+        let mut tree = QuadTree::new_empty(builder.rect_x, builder.rect_y, builder.rect_w, builder.rect_h, builder.levels());
+        tree.reserve_leaves(builder.leaf_capacity);
+        tree
+    }
+    fn insert_point(&mut self, x: CoordT, y: CoordT, data: DataT) {
+        self.insert(x, y, data);
+    }
+}
+
+impl<DataT, CoordT: Coord> SpatialIndex<DataT, CoordT> for QuadTree<DataT, CoordT> {
+    fn clear(&mut self) {
+        self.clear()
+    }
+    fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.insert(x, y, data)
+    }
+    fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        self.try_insert(x, y, data)
+    }
+    fn remove(&mut self, x: CoordT, y: CoordT, predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        self.remove(x, y, predicate)
+    }
+    fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.broad_phase(x, y)
+    }
+    fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.broad_phase_mut(x, y)
+    }
+    fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase(x, y)
+    }
+    fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase_mut(x, y)
+    }
+    fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        self.query_rect(x, y, w, h)
+    }
+}
+
+use super::{GetX, GetY, GetWidth, GetHeight};
+
+impl<Entity: GetX<CoordT>+GetY<CoordT>+GetWidth<CoordT>+GetHeight<CoordT>, CoordT: Coord> QuadTree<Entity, CoordT> {
+
+    /// Fill an old tree with references to the entities of a new borrow of the model, using their bounding boxes.
+    /// The old tree is recycled, so its shape and the capacities of its buckets are kept.
+    pub fn refill_from_model<'a>(tree: QuadTree<&mut Entity, CoordT>, model: &'a mut [Entity]) -> QuadTree<&'a mut Entity, CoordT> {
+        let mut tree = tree.recycle();
+        for entity in model.iter_mut() {
+            tree.insert_box(entity.get_x(), entity.get_y(), entity.get_width(), entity.get_height(), entity);
+        }
+        tree
+    }
+
+    /// Fill the tree with references to the model, and call f with it. The tree is emptied again when f returns,
+    /// so it can be kept from frame to frame next to the model without borrowing it. If f panics, the tree is
+    /// left as a single empty node.
+    pub fn with_tree<'a, R>(tree: &mut QuadTree<&mut Entity, CoordT>, model: &'a mut [Entity], f: impl FnOnce(&mut QuadTree<&'a mut Entity, CoordT>) -> R) -> R {
+        let empty = QuadTree::new_empty(CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, 0);
+        let mut filled = QuadTree::refill_from_model(std::mem::replace(tree, empty), model);
+        let result = f(&mut filled);
+        *tree = filled.recycle();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixed_depth_quad_tree;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// The depth of the node that holds the item, and the rect of that node
+    fn node_of(tree: &QuadTree<usize>, item: usize, depth: u16) -> Option<(u16, Rect<u16>)> {
+        if tree.data.contains(&item) {
+            return Some((depth, tree.rect()));
+        }
+        tree.quads.as_ref()?.iter().find_map(|quad| node_of(quad, item, depth + 1))
+    }
+
+    #[test]
+    fn points_descend_to_the_leaves_of_the_other_trees() {
+        //an odd size gives the right and bottom quadrants the extra unit, and the points sit on every border
+        let mut loose: QuadTree<(u16, u16)> = QuadTree::new_empty(3, 5, 101, 101, 3);
+        let mut fixed: fixed_depth_quad_tree::QuadTree<(u16, u16)> = fixed_depth_quad_tree::QuadTree::new_empty(3, 5, 101, 101);
+        let points: Vec<(u16, u16)> = (3..104).step_by(2).flat_map(|x| (5..106).step_by(3).map(move |y| (x, y))).collect();
+        for &(x, y) in &points {
+            assert!(loose.insert(x, y, (x, y)) && fixed.insert(x, y, (x, y)));
+        }
+        for &(x, y) in &points {
+            assert_eq!(loose.broad_phase(x, y), fixed.broad_phase(x, y), "{:?}", (x, y));
+        }
+    }
+
+    #[test]
+    fn boxes_sink_to_the_deepest_node_whose_loose_bounds_fit_them() {
+        //16x16 leaves, whose loose bounds are 32x32
+        let mut tree = QuadTree::new_empty(0, 0, 128, 128, 3);
+        let boxes = [
+            (2, 2, 4, 4),
+            //crosses the border between two leaves, but fits the loose bounds of the one that holds its center
+            (14, 14, 8, 8),
+            //too large for the loose bounds of a leaf
+            (5, 5, 30, 30),
+            //too large for any child of the root
+            (0, 0, 100, 100),
+            //hangs over the far edge of the tree, too far for the loose bounds of the bottom right quadrant
+            (100, 100, 100, 100),
+        ];
+        for (i, &(x, y, w, h)) in boxes.iter().enumerate() {
+            assert!(tree.insert_box(x, y, w, h, i));
+        }
+        assert_eq!(node_of(&tree, 0, 0), Some((3, (0, 0, 16, 16))));
+        assert_eq!(node_of(&tree, 1, 0), Some((3, (16, 16, 16, 16))));
+        assert_eq!(node_of(&tree, 2, 0), Some((2, (0, 0, 32, 32))));
+        assert_eq!(node_of(&tree, 3, 0), Some((0, (0, 0, 128, 128))));
+        assert_eq!(node_of(&tree, 4, 0), Some((0, (0, 0, 128, 128))));
+        //boxes kept at inner nodes and at the root are found by remove
+        assert_eq!(tree.remove(5, 5, |_| true), vec![2]);
+        assert_eq!(tree.remove(100, 100, |_| true), vec![4]);
+        assert_eq!(node_of(&tree, 2, 0), None);
+        assert_eq!(tree.query_box(0, 0, 128, 128).len(), 3);
+    }
+
+    #[test]
+    fn query_box_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let boxes: Vec<Rect<u16>> = (0..400).map(|_| (rng.gen_range(0..128), rng.gen_range(0..128), rng.gen_range(0..40), rng.gen_range(0..40))).collect();
+        let mut tree = QuadTree::new_empty(0, 0, 128, 128, 4);
+        for (i, &(x, y, w, h)) in boxes.iter().enumerate() {
+            assert!(tree.insert_box(x, y, w, h, i));
+        }
+        for _ in 0..200 {
+            let query = (rng.gen_range(0..140), rng.gen_range(0..140), rng.gen_range(0..30), rng.gen_range(0..30));
+            let mut found: Vec<usize> = tree.query_box(query.0, query.1, query.2, query.3).into_iter().copied().collect();
+            found.sort();
+            let expected: Vec<usize> = (0..boxes.len()).filter(|&i| rect::overlaps(query, boxes[i])).collect();
+            assert_eq!(found, expected, "{:?}", query);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use super::rect::{self, Rect};
+use super::bucket::recycle_vec;
+use super::coord::Coord;
+use super::builder::{FromBuilder, QuadTreeBuilder};
+use super::error::QuadTreeError;
+use super::spatial_index::SpatialIndex;
+
+/// A linear QuadTree. The leaves of a tree of the given depth are kept in one flat Vec, in Z-order: the index of
+/// a leaf is the Morton code of its column and row, which interleaves their bits. A leaf is found by dividing the
+/// position into a column and row, so broad_phase takes the same time at any depth, and walking the Vec visits
+/// the leaves quadrant by quadrant like a recursive tree would.
+/// Leaves are square. With integer coordinates their side is a power of two of at least one unit, so leaves never
+/// split a unit, with floats the side is the size of the tree divided by 2^depth.
+pub struct QuadTree<DataT, CoordT: Coord = u16> {
+    //Buckets of data within the tree, indexed by Morton code
+    leaves: Vec<Leaf<DataT, CoordT>>,
+    //The bounding box of the tree
+    rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT,
+    //How many times the tree is divided into quadrants
+    depth: u16,
+    //The side of a leaf
+    leaf_size: f64,
+}
+
+/// Leaf of the linear QuadTree
+struct Leaf<DataT, CoordT: Coord> {
+    data: Vec<DataT>,
+    //The positions of the data elements, stored separately like in the cells of the Grid
+    positions: Vec<(CoordT, CoordT)>,
+}
+
+impl<DataT, CoordT: Coord> Leaf<DataT, CoordT> {
+    fn recycle<OtherT>(mut self) -> Leaf<OtherT, CoordT> {
+        self.positions.clear();
+        Leaf { data: recycle_vec(self.data), positions: self.positions }
+    }
+}
+
+///Spread the lower 16 bits of v out to the even bits
+fn part_1_by_1(v: u32) -> u32 {
+    let mut v = v & 0x0000_ffff;
+    v = (v | (v << 8)) & 0x00ff_00ff;
+    v = (v | (v << 4)) & 0x0f0f_0f0f;
+    v = (v | (v << 2)) & 0x3333_3333;
+    (v | (v << 1)) & 0x5555_5555
+}
+
+///Interleave the bits of a column and row, the column takes the even bits
+fn morton_code(column: u32, row: u32) -> usize {
+    (part_1_by_1(column) | (part_1_by_1(row) << 1)) as usize
+}
+
+impl<DataT, CoordT: Coord> QuadTree<DataT, CoordT> {
+    /// The deepest tree, whose Morton codes of 2 * 15 bits still fit in a u32
+    pub const MAX_DEPTH: u16 = 15;
+    /// Construct a tree with 4^depth empty leaves. With integer coordinates the leaves are the smallest power of two
+    /// that lets 2^depth of them cover the width and the height, so the grid can reach past the far edges of the
+    /// tree. With floats 2^depth leaves cover the larger of the width and height exactly.
+    pub fn new_empty(rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT, depth: u16) -> Self {
+        assert!(depth <= Self::MAX_DEPTH, "a depth of {} is too deep for a linear QuadTree", depth);
+        let size = if rect_w > rect_h { rect_w } else { rect_h }.to_f64();
+        let side = (1u64 << depth) as f64;
+        let leaf_size = if CoordT::STEP == CoordT::ZERO {
+            if size > 0.0 { size / side } else { 1.0 }
+        } else {
+            let mut leaf_size = 1.0;
+            while leaf_size * side < size {
+                leaf_size *= 2.0;
+            }
+            leaf_size
+        };
+        let leaves = (0..1usize << (2 * depth)).map(|_| Leaf { data: Vec::new(), positions: Vec::new() }).collect();
+        QuadTree { leaves, rect_x, rect_y, rect_w, rect_h, depth, leaf_size }
+    }
+    /// How many times the tree is divided into quadrants
+    pub fn depth(&self) -> u16 {
+        self.depth
+    }
+    /// The width and height of a leaf
+    pub fn leaf_size(&self) -> f64 {
+        self.leaf_size
+    }
+    /// Remove all points from all leaves
+    pub fn clear(&mut self) {
+        for leaf in self.leaves.iter_mut() {
+            leaf.data.clear();
+            leaf.positions.clear();
+        }
+    }
+    /// Empty the tree and give it a new item type, keeping the capacity of its buckets.
+    /// This lets a tree of references into a model be filled again for the next borrow of the model.
+    pub fn recycle<OtherT>(self) -> QuadTree<OtherT, CoordT> {
+        let QuadTree { leaves, rect_x, rect_y, rect_w, rect_h, depth, leaf_size } = self;
+        QuadTree { leaves: leaves.into_iter().map(Leaf::recycle).collect(), rect_x, rect_y, rect_w, rect_h, depth, leaf_size }
+    }
+    fn reserve_leaves(&mut self, additional: usize) {
+        for leaf in self.leaves.iter_mut() {
+            leaf.data.reserve(additional);
+            leaf.positions.reserve(additional);
+        }
+    }
+    fn bounds(&self) -> Rect<CoordT> {
+        (self.rect_x, self.rect_y, self.rect_w, self.rect_h)
+    }
+    /// The index in Z-order of the leaf that contains the point, or None if it is outside the tree
+    pub fn leaf_index(&self, x: CoordT, y: CoordT) -> Option<usize> {
+        if !rect::contains(self.bounds(), x, y) {
+            return None;
+        }
+        Some(morton_code(self.column_of((x - self.rect_x).to_f64()), self.column_of((y - self.rect_y).to_f64())))
+    }
+    /// The column or row of an offset from the top left corner of the tree
+    fn column_of(&self, offset: f64) -> u32 {
+        //float division can round a point just before the far edge up into the next column
+        ((offset / self.leaf_size) as u32).min((1u32 << self.depth) - 1)
+    }
+    /// Call f on every leaf that overlaps the rect
+    fn for_each_leaf_touching<'a>(&'a self, query: Rect<CoordT>, mut f: impl FnMut(&'a Leaf<DataT, CoordT>)) {
+        if !rect::overlaps(self.bounds(), query) {
+            return;
+        }
+        //measured in f64 from the top left corner of the tree, so the far edges of the query can not overflow
+        let (start_x, start_y) = ((query.0.to_f64() - self.rect_x.to_f64()).max(0.0), (query.1.to_f64() - self.rect_y.to_f64()).max(0.0));
+        let (end_x, end_y) = (query.0.to_f64() - self.rect_x.to_f64() + query.2.to_f64(), query.1.to_f64() - self.rect_y.to_f64() + query.3.to_f64());
+        let columns = self.column_of(start_x)..=self.column_of(end_x);
+        for row in self.column_of(start_y)..=self.column_of(end_y) {
+            for column in columns.clone() {
+                f(&self.leaves[morton_code(column, row)]);
+            }
+        }
+    }
+    /// Insert a point into the correct leaf, or return false if it doesn't fit
+    pub fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.try_insert(x, y, data).is_ok()
+    }
+    /// Insert a point into the correct leaf. If it doesn't fit, the data is given back with the error, so
+    /// the caller can keep it or store it elsewhere.
+    pub fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        match self.leaf_index(x, y) {
+            Some(index) => {
+                self.leaves[index].data.push(data);
+                self.leaves[index].positions.push((x, y));
+                Ok(())
+            }
+            None => Err((data, QuadTreeError::OutOfBounds { x, y, rect: self.bounds() })),
+        }
+    }
+    /// Remove the items stored at exactly (x, y) for which the predicate returns true, and return them.
+    /// Only the leaf that owns the point is searched.
+    pub fn remove(&mut self, x: CoordT, y: CoordT, mut predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        let mut removed = Vec::new();
+        let Some(index) = self.leaf_index(x, y) else { return removed };
+        let leaf = &mut self.leaves[index];
+        let mut i = 0;
+        while i < leaf.data.len() {
+            if leaf.positions[i] == (x, y) && predicate(&leaf.data[i]) {
+                leaf.positions.swap_remove(i);
+                removed.push(leaf.data.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        removed
+    }
+    /// Return every item whose stored position is inside the rect. Only the leaves that overlap the rect are visited.
+    pub fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_leaf_touching(query, |leaf| {
+            for (item, &(item_x, item_y)) in leaf.data.iter().zip(&leaf.positions) {
+                if rect::contains(query, item_x, item_y) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return a reference to the vec of data of the leaf that contains the point.
+    /// Panics if the point is outside the tree, see try_broad_phase.
+    pub fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.try_broad_phase(x, y).unwrap()
+    }
+    /// Return a mutable reference to the vec of data of the leaf that contains the point.
+    /// Panics if the point is outside the tree, see try_broad_phase_mut.
+    pub fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.try_broad_phase_mut(x, y).unwrap()
+    }
+    /// Return a reference to the vec of data of the leaf that contains the point, or OutOfBounds
+    pub fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        let index = self.leaf_index(x, y).ok_or(QuadTreeError::OutOfBounds { x, y, rect: self.bounds() })?;
+        Ok(&self.leaves[index].data)
+    }
+    /// Mutable version of try_broad_phase
+    pub fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        let index = self.leaf_index(x, y).ok_or(QuadTreeError::OutOfBounds { x, y, rect: self.bounds() })?;
+        Ok(&mut self.leaves[index].data)
+    }
+    /// Call f on the data of every leaf in Z-order, so neighbouring leaves are mostly visited one after another
+    pub fn for_each_leaf(&self, mut f: impl FnMut(&[DataT])) {
+        for leaf in self.leaves.iter() {
+            f(&leaf.data);
+        }
+    }
+    /// Mutable version of for_each_leaf. The leaves are handed out as slices, so items can be changed but not
+    /// added or removed.
+    pub fn for_each_leaf_mut(&mut self, mut f: impl FnMut(&mut [DataT])) {
+        for leaf in self.leaves.iter_mut() {
+            f(&mut leaf.data);
+        }
+    }
+}
+
+/// The depth of the builder is cut to MAX_DEPTH, so a small leaf size gives the deepest tree instead of a panic
+impl<DataT, CoordT: Coord> FromBuilder<DataT, CoordT> for QuadTree<DataT, CoordT> {
+    fn from_builder(builder: &QuadTreeBuilder<CoordT>) -> Self {
+        let depth = builder.levels().min(Self::MAX_DEPTH);
+        let mut tree = QuadTree::new_empty(builder.rect_x, builder.rect_y, builder.rect_w, builder.rect_h, depth);
+        tree.reserve_leaves(builder.leaf_capacity);
+        tree
+    }
+    fn insert_point(&mut self, x: CoordT, y: CoordT, data: DataT) {
+        self.insert(x, y, data);
+    }
+}
+
+impl<DataT, CoordT: Coord> SpatialIndex<DataT, CoordT> for QuadTree<DataT, CoordT> {
+    fn clear(&mut self) {
+        self.clear()
+    }
+    fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.insert(x, y, data)
+    }
+    fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        self.try_insert(x, y, data)
+    }
+    fn remove(&mut self, x: CoordT, y: CoordT, predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        self.remove(x, y, predicate)
+    }
+    fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.broad_phase(x, y)
+    }
+    fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.broad_phase_mut(x, y)
+    }
+    fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase(x, y)
+    }
+    fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase_mut(x, y)
+    }
+    fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        self.query_rect(x, y, w, h)
+    }
+}
+
+use super::{GetX, GetY};
+
+impl<Entity: GetX<CoordT>+GetY<CoordT>, CoordT: Coord> QuadTree<Entity, CoordT> {
+
+    /// Rebuild a QuadTree from a model. This is used to update the tree after the model has changed.
+    /// It clears the tree, then puts references to the entities back in it, however it does not
+    /// change the capacities of the underlying Vecs.
+    /// This aliases the model through raw pointers, see with_tree for a version tied to the borrow of the model.
+    /// Returns how many entities were left out because they are outside the tree.
+    ///
+    /// # Safety
+    /// The references put in the tree are not tied to the borrow of the model. Until the tree is cleared or
+    /// rebuilt, the model must not be moved, dropped or resized, and an entity must not be reached through the
+    /// tree while a reference to it taken from the model is alive, or the other way around. A loop over
+    /// model.iter_mut() that calls broad_phase_mut breaks this, because the leaf holds the entity being updated.
+    pub unsafe fn rebuild_from_model(tree: &mut QuadTree<&mut Entity, CoordT>, model: &mut [Entity]) -> usize {
+        tree.clear();
+        let mut outside = 0;
+        for entity in model.iter_mut() {
+            let entity = entity as *mut Entity;
+            //// SAFETY: The tree is cleared before being filled, and the caller keeps the model in place.
+            let entity = unsafe {&mut *entity};
+            //insert a reference to the entity into the tree
+            if !tree.insert(entity.get_x(), entity.get_y(), entity) {
+                outside += 1;
+            }
+        }
+        outside
+    }
+
+    /// Build a new QuadTree of references to the entities. The tree borrows the model, so the model can not
+    /// be dropped or changed while the tree is alive.
+    pub fn build_from_model(model: &mut [Entity], rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT, depth: u16) -> QuadTree<&mut Entity, CoordT> {
+        QuadTree::refill_from_model(QuadTree::new_empty(rect_x, rect_y, rect_w, rect_h, depth), model)
+    }
+
+    /// Fill an old tree with references to the entities of a new borrow of the model. The old tree is recycled,
+    /// so the capacities of its buckets are kept like in rebuild_from_model.
+    pub fn refill_from_model<'a>(tree: QuadTree<&mut Entity, CoordT>, model: &'a mut [Entity]) -> QuadTree<&'a mut Entity, CoordT> {
+        let mut tree = tree.recycle();
+        for entity in model.iter_mut() {
+            tree.insert(entity.get_x(), entity.get_y(), entity);
+        }
+        tree
+    }
+
+    /// Fill the tree with references to the model, and call f with it. The tree is emptied again when f returns,
+    /// so it can be kept from frame to frame next to the model without borrowing it. If f panics, the tree is
+    /// left as a single empty leaf covering no area.
+    pub fn with_tree<'a, R>(tree: &mut QuadTree<&mut Entity, CoordT>, model: &'a mut [Entity], f: impl FnOnce(&mut QuadTree<&'a mut Entity, CoordT>) -> R) -> R {
+        let empty = QuadTree::new_empty(CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, 0);
+        let mut filled = QuadTree::refill_from_model(std::mem::replace(tree, empty), model);
+        let result = f(&mut filled);
+        *tree = filled.recycle();
+        result
+    }
+}
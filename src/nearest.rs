@@ -0,0 +1,61 @@
+//! Bookkeeping shared by the nearest neighbour searches of every backend.
+
+use std::cmp::Ordering;
+
+/// The k closest items seen so far, sorted by squared distance
+pub(crate) struct KNearest<'a, DataT> {
+    k: usize,
+    found: Vec<(f64, &'a DataT)>,
+}
+
+impl<'a, DataT> KNearest<'a, DataT> {
+    pub(crate) fn new(k: usize) -> Self {
+        KNearest { k, found: Vec::with_capacity(k) }
+    }
+    /// Only things closer than this squared distance can still make it into the result.
+    /// Once k items are found, this is the distance of the worst of them.
+    pub(crate) fn bound(&self) -> f64 {
+        if self.k == 0 {
+            0.0
+        } else if self.found.len() < self.k {
+            f64::INFINITY
+        } else {
+            self.found[self.k - 1].0
+        }
+    }
+    /// Return true if something at this squared distance could still make it into the result
+    pub(crate) fn wants(&self, distance_squared: f64) -> bool {
+        distance_squared < self.bound()
+    }
+    /// Keep the item if it is one of the k closest so far. Ties keep the item that was offered first.
+    pub(crate) fn offer(&mut self, distance_squared: f64, item: &'a DataT) {
+        if !self.wants(distance_squared) {
+            return;
+        }
+        let index = self.found.partition_point(|(d2, _)| *d2 <= distance_squared);
+        self.found.insert(index, (distance_squared, item));
+        self.found.truncate(self.k);
+    }
+    pub(crate) fn into_vec(self) -> Vec<(f64, &'a DataT)> {
+        self.found
+    }
+}
+
+/// A squared distance and the index of a node, ordered so the closest node is the greatest.
+/// This lets a BinaryHeap pop the closest node first.
+#[derive(PartialEq)]
+pub(crate) struct Closest(pub(crate) f64, pub(crate) usize);
+
+impl Eq for Closest {}
+
+impl PartialOrd for Closest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Closest {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.total_cmp(&self.0).then(other.1.cmp(&self.1))
+    }
+}
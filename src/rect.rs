@@ -0,0 +1,261 @@
+//! Helpers for the (x, y, w, h) tuples used to describe rects throughout the crate.
+//! Rects are half open, so a rect covers x..x+w and y..y+h.
+//! This is also the rule for who owns an edge: a point on the border between two leaves or cells belongs to the
+//! one that starts there, to the right of or below the border, and a point on the far edge of a tree is outside it.
+//! The enum, fixed depth and loose trees split their rects with divide_into_4, so two of them with the same bounds
+//! and depth put a point in the same leaf. The Morton tree and the Grid size their leaves and cells on their own,
+//! see their docs, so only the rule at the borders is the same for them.
+//! Comparisons subtract the smaller start from the larger one instead of adding a size to a start, so rects
+//! touching the end of an unsigned range do not overflow. The difference is checked, so signed points far from
+//! a rect do not overflow either, they are just too far to be inside it. Distances are measured in f64.
+
+use super::coord::Coord;
+
+pub(crate) type Rect<CoordT> = (CoordT, CoordT, CoordT, CoordT);
+
+/// Return true if at is less than len past start, which it must not be below. An offset too large for the type
+/// is past every len.
+fn offset_below<CoordT: Coord>(at: CoordT, start: CoordT, len: CoordT) -> bool {
+    at.checked_offset(start).is_some_and(|offset| offset < len)
+}
+
+/// Return true if the point is inside the rect
+pub(crate) fn contains<CoordT: Coord>(rect: Rect<CoordT>, x: CoordT, y: CoordT) -> bool {
+    let (rect_x, rect_y, rect_w, rect_h) = rect;
+    x >= rect_x && offset_below(x, rect_x, rect_w) &&
+    y >= rect_y && offset_below(y, rect_y, rect_h)
+}
+
+/// Split a rect into 4 quadrants, in the order top left, top right, bottom left, bottom right. For odd sizes the
+/// right and bottom quadrants get the extra unit, so the quadrants always cover the whole rect.
+pub(crate) fn divide_into_4<CoordT: Coord>(rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT) -> [Rect<CoordT>; 4] {
+    let half_w = rect_w.half();
+    let half_h = rect_h.half();
+    let rest_w = rect_w - half_w;
+    let rest_h = rect_h - half_h;
+    [
+        (rect_x, rect_y, half_w, half_h),
+        (rect_x + half_w, rect_y, rest_w, half_h),
+        (rect_x, rect_y + half_h, half_w, rest_h),
+        (rect_x + half_w, rect_y + half_h, rest_w, rest_h)
+    ]
+}
+
+/// Return the index of the quadrant of divide_into_4 that owns a point of the rect. A point on a midline belongs to
+/// the right or bottom quadrant, and descending a tree takes one comparison per axis and level.
+pub(crate) fn quadrant_of<CoordT: Coord>(rect: Rect<CoordT>, x: CoordT, y: CoordT) -> usize {
+    let mid_x = rect.0 + rect.2.half();
+    let mid_y = rect.1 + rect.3.half();
+    (x >= mid_x) as usize + 2 * (y >= mid_y) as usize
+}
+
+/// Return true if the spans a..a+a_len and b..b+b_len share a point. A zero length span is a single point.
+fn spans_overlap<CoordT: Coord>(a: CoordT, a_len: CoordT, b: CoordT, b_len: CoordT) -> bool {
+    if a <= b {
+        a == b || offset_below(b, a, a_len)
+    } else {
+        offset_below(a, b, b_len)
+    }
+}
+
+/// Return true if the two rects share at least one point. A rect with no width or height is treated as
+/// a line or point, so items inserted as a point (with a size of 0) are found by the box queries.
+pub(crate) fn overlaps<CoordT: Coord>(a: Rect<CoordT>, b: Rect<CoordT>) -> bool {
+    spans_overlap(a.0, a.2, b.0, b.2) && spans_overlap(a.1, a.3, b.1, b.3)
+}
+
+/// Return true if the span inner..inner+inner_len lies completely inside the span outer..outer+outer_len
+fn span_fits<CoordT: Coord>(inner: CoordT, inner_len: CoordT, outer: CoordT, outer_len: CoordT) -> bool {
+    inner >= outer && inner.checked_offset(outer).is_some_and(|offset| offset <= outer_len && inner_len <= outer_len - offset)
+}
+
+/// Return true if the inner rect lies completely inside the outer rect
+pub(crate) fn fits<CoordT: Coord>(inner: Rect<CoordT>, outer: Rect<CoordT>) -> bool {
+    span_fits(inner.0, inner.2, outer.0, outer.2) && span_fits(inner.1, inner.3, outer.1, outer.3)
+}
+
+/// Move a coordinate to the closest one inside the span start..start+len, which must not be empty
+pub(crate) fn clamp<CoordT: Coord>(at: CoordT, start: CoordT, len: CoordT) -> CoordT {
+    let last = (start + len).prev();
+    if at < start { start } else if at > last { last } else { at }
+}
+
+/// (a + b) % len for a and b in 0..len, without overflowing
+fn add_wrapped<CoordT: Coord>(a: CoordT, b: CoordT, len: CoordT) -> CoordT {
+    if a >= len - b { a - (len - b) } else { a + b }
+}
+
+/// How far at is past start, which it must not be below, modulo len. When the offset does not fit in the
+/// type it is put together from the remainders of the two instead.
+fn offset_rem<CoordT: Coord>(at: CoordT, start: CoordT, len: CoordT) -> CoordT {
+    match at.checked_offset(start) {
+        Some(offset) => offset % len,
+        //only signed coordinates on either side of zero are that far apart, so at % len is not negative
+        None => add_wrapped(at % len, CoordT::ZERO - start % len, len),
+    }
+}
+
+/// Wrap a coordinate around the span start..start+len, which must not be empty, so leaving it on one side
+/// enters it on the other
+pub(crate) fn wrap<CoordT: Coord>(at: CoordT, start: CoordT, len: CoordT) -> CoordT {
+    let wrapped = if at >= start {
+        start + offset_rem(at, start, len)
+    } else {
+        let back = offset_rem(start, at, len);
+        if back == CoordT::ZERO { start } else { start + (len - back) }
+    };
+    //floats can round onto the far edge
+    if wrapped - start < len { wrapped } else { start }
+}
+
+/// Move a coordinate back by a distance around the span start..start+len, which must not be empty
+pub(crate) fn wrap_back<CoordT: Coord>(at: CoordT, by: CoordT, start: CoordT, len: CoordT) -> CoordT {
+    let (offset, by) = (wrap(at, start, len) - start, by % len);
+    if offset >= by { start + (offset - by) } else { start + (len - (by - offset)) }
+}
+
+/// Split a span on a wrapping range start..start+len into the part up to the far end of the range, and the
+/// part that goes on from the start, if any. A span longer than the range covers all of it.
+fn wrap_span<CoordT: Coord>(at: CoordT, span: CoordT, start: CoordT, len: CoordT) -> ((CoordT, CoordT), Option<(CoordT, CoordT)>) {
+    let at = wrap(at, start, len);
+    let span = if span > len { len } else { span };
+    let to_end = len - (at - start);
+    if span > to_end {
+        ((at, to_end), Some((start, span - to_end)))
+    } else {
+        ((at, span), None)
+    }
+}
+
+/// Split a query rect on a world that wraps around at its edges into up to 4 rects inside the world, which do
+/// not overlap. The start of the query is wrapped into the world first. An empty world gives no rects.
+pub(crate) fn wrap_pieces<CoordT: Coord>(query: Rect<CoordT>, world: Rect<CoordT>) -> Vec<Rect<CoordT>> {
+    let (world_x, world_y, world_w, world_h) = world;
+    if world_w <= CoordT::ZERO || world_h <= CoordT::ZERO {
+        return Vec::new();
+    }
+    let (first_x, second_x) = wrap_span(query.0, query.2, world_x, world_w);
+    let (first_y, second_y) = wrap_span(query.1, query.3, world_y, world_h);
+    let mut pieces = Vec::with_capacity(4);
+    for (x, w) in std::iter::once(first_x).chain(second_x) {
+        for (y, h) in std::iter::once(first_y).chain(second_y) {
+            pieces.push((x, y, w, h));
+        }
+    }
+    pieces
+}
+
+/// The center wrapped into a world that wraps around at its edges, and the pieces of the square around the
+/// circle, see wrap_pieces
+pub(crate) fn wrap_circle<CoordT: Coord>(world: Rect<CoordT>, center_x: CoordT, center_y: CoordT, radius: CoordT) -> (CoordT, CoordT, Vec<Rect<CoordT>>) {
+    let (world_x, world_y, world_w, world_h) = world;
+    if world_w <= CoordT::ZERO || world_h <= CoordT::ZERO {
+        return (center_x, center_y, Vec::new());
+    }
+    let (center_x, center_y) = (wrap(center_x, world_x, world_w), wrap(center_y, world_y, world_h));
+    //a square as wide as the world would overflow small integer types, and covers all of it anyway
+    let around = |center: CoordT, start: CoordT, len: CoordT| {
+        if 2.0 * radius.to_f64() >= len.to_f64() { (start, len) } else { (wrap_back(center, radius, start, len), radius + radius + CoordT::STEP) }
+    };
+    let ((x, w), (y, h)) = (around(center_x, world_x, world_w), around(center_y, world_y, world_h));
+    (center_x, center_y, wrap_pieces((x, y, w, h), world))
+}
+
+/// The squared distance between two points inside a world that wraps around at its edges, going the short
+/// way around on each axis
+pub(crate) fn distance_squared_wrapped<CoordT: Coord>(world: Rect<CoordT>, ax: CoordT, ay: CoordT, bx: CoordT, by: CoordT) -> f64 {
+    let short = |a: CoordT, b: CoordT, len: CoordT| {
+        let d = (a.to_f64() - b.to_f64()).abs();
+        d.min(len.to_f64() - d)
+    };
+    let (dx, dy) = (short(ax, bx, world.2), short(ay, by, world.3));
+    dx * dx + dy * dy
+}
+
+/// Return true if the spans a..a+a_len and b..b+b_len overlap on a range start..start+len that wraps around,
+/// which must not be empty. Like the spans of is_coliding, spans that only touch do not overlap.
+pub(crate) fn spans_overlap_wrapped<CoordT: Coord>(a: CoordT, a_len: CoordT, b: CoordT, b_len: CoordT, start: CoordT, len: CoordT) -> bool {
+    let (a, b) = (wrap(a, start, len) - start, wrap(b, start, len) - start);
+    //how far b starts after a, going forward around the range
+    let d = if b >= a { b - a } else { len - (a - b) };
+    (d < a_len && (d > CoordT::ZERO || b_len > CoordT::ZERO)) || len - d < b_len
+}
+
+/// The squared distance between two points
+pub(crate) fn distance_squared<CoordT: Coord>(ax: CoordT, ay: CoordT, bx: CoordT, by: CoordT) -> f64 {
+    let dx = ax.to_f64() - bx.to_f64();
+    let dy = ay.to_f64() - by.to_f64();
+    dx * dx + dy * dy
+}
+
+/// The distance from a point to the closest point inside the span start..start+len
+fn distance_to_span<CoordT: Coord>(start: CoordT, len: CoordT, at: CoordT) -> f64 {
+    if at < start {
+        start.to_f64() - at.to_f64()
+    } else if offset_below(at, start, len) {
+        0.0
+    } else {
+        //the last point of an integer span is one step before its end
+        at.to_f64() - (start.to_f64() + len.to_f64() - CoordT::STEP.to_f64())
+    }
+}
+
+/// The squared distance from a point to the closest point inside the rect, or None for an empty rect
+pub(crate) fn distance_squared_to<CoordT: Coord>(rect: Rect<CoordT>, x: CoordT, y: CoordT) -> Option<f64> {
+    let (rect_x, rect_y, rect_w, rect_h) = rect;
+    if rect_w <= CoordT::ZERO || rect_h <= CoordT::ZERO {
+        return None;
+    }
+    let dx = distance_to_span(rect_x, rect_w, x);
+    let dy = distance_to_span(rect_y, rect_h, y);
+    Some(dx * dx + dy * dy)
+}
+
+/// Return true if any point of the rect is within the circle
+pub(crate) fn overlaps_circle<CoordT: Coord>(rect: Rect<CoordT>, center_x: CoordT, center_y: CoordT, radius: CoordT) -> bool {
+    let radius = radius.to_f64();
+    distance_squared_to(rect, center_x, center_y).is_some_and(|d2| d2 <= radius * radius)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_enters_on_the_other_side() {
+        assert_eq!(wrap(130u16, 0, 128), 2);
+        assert_eq!(wrap(-1i32, 0, 128), 127);
+        assert_eq!(wrap(-128i32, 0, 128), 0);
+        assert_eq!(wrap(64i32, -64, 128), -64);
+        //offsets from the start that do not fit in an i32
+        assert_eq!(wrap(i32::MAX, -64, 128), -1);
+        assert_eq!(wrap(i32::MIN, 64, 128), 128);
+        assert_eq!(wrap_back(2u16, 5, 0, 128), 125);
+        assert_eq!(wrap_back(2u16, 130, 0, 128), 0);
+    }
+
+    #[test]
+    fn wrap_pieces_split_at_the_far_edges() {
+        let world = (0u16, 0, 128, 128);
+        assert_eq!(wrap_pieces((120, 124, 16, 8), world), vec![(120, 124, 8, 4), (120, 0, 8, 4), (0, 124, 8, 4), (0, 0, 8, 4)]);
+        assert_eq!(wrap_pieces((10, 250, 4, 4), world), vec![(10, 122, 4, 4)]);
+        //a query longer than the world covers all of it once
+        assert_eq!(wrap_pieces((5, 0, 300, 1), world), vec![(5, 0, 123, 1), (0, 0, 5, 1)]);
+        assert!(wrap_pieces((0, 0, 1, 1), (0u16, 0, 0, 128)).is_empty());
+    }
+
+    #[test]
+    fn wrap_circle_bigger_than_half_the_world_covers_it() {
+        let world = (0u16, 0, 128, 128);
+        assert_eq!(wrap_circle(world, 130, 3, 70), (2, 3, vec![world]));
+        assert_eq!(wrap_circle(world, 1, 126, 4), (1, 126, vec![(125, 122, 3, 6), (125, 0, 3, 3), (0, 122, 6, 6), (0, 0, 6, 3)]));
+    }
+
+    #[test]
+    fn wrapped_distances_go_the_short_way_around() {
+        let world = (-64i32, -64, 128, 128);
+        assert_eq!(distance_squared_wrapped(world, 63, -64, -64, -64), 1.0);
+        assert_eq!(distance_squared_wrapped(world, -64, -64, 0, 0), 2.0 * 64.0 * 64.0);
+        assert_eq!(distance_squared_wrapped(world, -60, 60, 60, -60), 2.0 * 8.0 * 8.0);
+    }
+}
@@ -0,0 +1,98 @@
+//! A common interface for the broad phase backends, so game code and benchmarks can be written once and the
+//! backend picked by a type parameter.
+
+use super::coord::Coord;
+use super::builder::{FromBuilder, QuadTreeBuilder};
+use super::error::QuadTreeError;
+use super::{GetX, GetY};
+
+/// The operations shared by the backends. Every index covers a rect, and items are stored at a point.
+pub trait SpatialIndex<DataT, CoordT: Coord = u16>: FromBuilder<DataT, CoordT> {
+    /// Construct an empty index with the given bounds. The shape of the index is the default of
+    /// QuadTreeBuilder, build it with a builder to pick another one.
+    fn new(rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT) -> Self {
+        QuadTreeBuilder::new(rect_w, rect_h).origin(rect_x, rect_y).build_empty()
+    }
+    /// Remove all items, keeping the capacity of the buckets
+    fn clear(&mut self);
+    /// Insert an item at a point, or return false if it is left out because the point is outside the index
+    fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool;
+    /// Insert an item at a point, or give it back with OutOfBounds if the point is outside the index
+    fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)>;
+    /// Remove the items stored at exactly (x, y) for which the predicate returns true, and return them
+    fn remove(&mut self, x: CoordT, y: CoordT, predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT>;
+    /// Return the bucket of items that contains the point. Items with a box that a tree keeps above its leaves
+    /// are not in it.
+    fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT>;
+    /// Mutable version of broad_phase
+    fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT>;
+    /// Return the bucket of items that contains the point, or OutOfBounds if the point is outside the index
+    fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>>;
+    /// Mutable version of try_broad_phase
+    fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>>;
+    /// Return every item whose stored position is inside the rect
+    fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT>;
+    /// Clear the index, then insert every item at its own position. Items outside the index are left out,
+    /// and their number is returned.
+    fn rebuild(&mut self, items: impl IntoIterator<Item = DataT>) -> usize where DataT: GetX<CoordT> + GetY<CoordT> {
+        self.clear();
+        let mut outside = 0;
+        for item in items {
+            if !self.insert(item.get_x(), item.get_y(), item) {
+                outside += 1;
+            }
+        }
+        outside
+    }
+}
+
+/// The queries of the trees beyond the shared broad phase, so wrappers like Bounded can offer them for either tree.
+/// Implemented by the enum and fixed depth trees.
+pub trait SpatialQueries<DataT, CoordT: Coord = u16>: SpatialIndex<DataT, CoordT> {
+    /// Mutable version of query_rect
+    fn query_rect_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT>;
+    /// Return every item whose box overlaps the query box. Items inserted as a point have a size of 0.
+    fn query_box(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT>;
+    /// Mutable version of query_box
+    fn query_box_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT>;
+    /// Return every item whose stored position is within radius of the center
+    fn query_radius(&self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&DataT>;
+    /// Mutable version of query_radius
+    fn query_radius_mut(&mut self, center_x: CoordT, center_y: CoordT, radius: CoordT) -> Vec<&mut DataT>;
+    /// Return up to k items closest to the point with their squared distances, closest first
+    fn k_nearest(&self, x: CoordT, y: CoordT, k: usize) -> Vec<(f64, &DataT)>;
+    /// Return every item of every bucket that the box touches
+    fn broad_phase_neighbourhood(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT>;
+    /// Mutable version of broad_phase_neighbourhood
+    fn broad_phase_neighbourhood_mut(&mut self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&mut DataT>;
+    /// Call f on every unordered pair of candidates once
+    fn for_each_pair_mut(&mut self, f: impl FnMut(&mut DataT, &mut DataT));
+}
+
+/// Fill any index with the positions of the entities in the model, like the index_model functions of the trees.
+/// The index borrows nothing, so it can be kept from frame to frame next to the model, and the entities are
+/// updated through the model, see update_entity_indexed.
+/// Returns how many entities were outside the index, like rebuild.
+pub fn index_model<Entity: GetX<CoordT> + GetY<CoordT>, CoordT: Coord, Index: SpatialIndex<usize, CoordT>>(index: &mut Index, model: &[Entity]) -> usize {
+    index.clear();
+    model.iter().enumerate().filter(|&(i, entity)| !index.insert(entity.get_x(), entity.get_y(), i)).count()
+}
+
+/// Rebuild any index from a model, like the rebuild_from_model functions of the trees. The index can be kept
+/// from frame to frame next to the model.
+/// This aliases the model through raw pointers, so the index must be rebuilt before it is used after the model changed.
+/// Returns how many entities were outside the index, like rebuild.
+///
+/// # Safety
+/// The caller picks the lifetime 'a of the references put in the index, and nothing ties it to the borrow of
+/// the model. Until the index is cleared or rebuilt, the model must not be moved, dropped or resized, and an
+/// entity must not be reached through the index while a reference to it taken from the model is alive, or the
+/// other way around. A loop over model.iter_mut() that queries the index breaks this, because the query can return
+/// the entity being updated. Use index_model for such loops, or with_tree and for_each_pair_mut.
+pub unsafe fn rebuild_from_model<'a, Entity: GetX<CoordT> + GetY<CoordT> + 'a, CoordT: Coord, Index: SpatialIndex<&'a mut Entity, CoordT>>(index: &mut Index, model: &mut [Entity]) -> usize {
+    index.rebuild(model.iter_mut().map(|entity| {
+        let entity = entity as *mut Entity;
+        //// SAFETY: The index is cleared before being filled, and the caller keeps the model in place.
+        unsafe {&mut *entity}
+    }))
+}
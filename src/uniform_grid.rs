@@ -0,0 +1,263 @@
+use super::rect::{self, Rect};
+use super::bucket::recycle_vec;
+use super::coord::Coord;
+use super::builder::{FromBuilder, QuadTreeBuilder};
+use super::error::QuadTreeError;
+use super::spatial_index::SpatialIndex;
+
+/// A uniform grid of square cells, stored row by row in one flat Vec. Unlike the trees there is no hierarchy
+/// to walk, the cell of a point is found with one division per axis. For evenly spread worlds this is the
+/// simplest broad phase, and a baseline for the trees.
+pub struct Grid<DataT, CoordT: Coord = u16> {
+    cells: Vec<Cell<DataT, CoordT>>,
+    //The bounding box of the grid
+    rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT,
+    //The width and height of every cell
+    cell_size: CoordT,
+    columns: usize,
+    rows: usize,
+}
+
+/// Cell of the Grid
+struct Cell<DataT, CoordT: Coord> {
+    data: Vec<DataT>,
+    //For simplicity the, positions of the data elements are stored separately from the data.
+    positions: Vec<(CoordT, CoordT)>,
+}
+
+impl<DataT, CoordT: Coord> Cell<DataT, CoordT> {
+    fn recycle<OtherT>(mut self) -> Cell<OtherT, CoordT> {
+        self.positions.clear();
+        Cell { data: recycle_vec(self.data), positions: self.positions }
+    }
+}
+
+impl<DataT, CoordT: Coord> Grid<DataT, CoordT> {
+    /// Construct an empty grid with cells of the given size. The last column and row reach past the far
+    /// edges of the grid when the size is not a multiple of the cell size.
+    pub fn new_empty(rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT, cell_size: CoordT) -> Self {
+        assert!(cell_size > CoordT::ZERO, "the cells of a grid must have a size");
+        let columns = ((rect_w.to_f64() / cell_size.to_f64()).ceil() as usize).max(1);
+        let rows = ((rect_h.to_f64() / cell_size.to_f64()).ceil() as usize).max(1);
+        let cells = (0..columns * rows).map(|_| Cell { data: Vec::new(), positions: Vec::new() }).collect();
+        Grid { cells, rect_x, rect_y, rect_w, rect_h, cell_size, columns, rows }
+    }
+    /// The width and height of every cell
+    pub fn cell_size(&self) -> CoordT {
+        self.cell_size
+    }
+    /// Remove all points from all cells
+    pub fn clear(&mut self) {
+        for cell in self.cells.iter_mut() {
+            cell.data.clear();
+            cell.positions.clear();
+        }
+    }
+    /// Empty the grid and give it a new item type, keeping the capacity of its cells.
+    /// This lets a grid of references into a model be filled again for the next borrow of the model.
+    pub fn recycle<OtherT>(self) -> Grid<OtherT, CoordT> {
+        let Grid { cells, rect_x, rect_y, rect_w, rect_h, cell_size, columns, rows } = self;
+        Grid { cells: cells.into_iter().map(Cell::recycle).collect(), rect_x, rect_y, rect_w, rect_h, cell_size, columns, rows }
+    }
+    fn reserve_cells(&mut self, additional: usize) {
+        for cell in self.cells.iter_mut() {
+            cell.data.reserve(additional);
+            cell.positions.reserve(additional);
+        }
+    }
+    fn bounds(&self) -> Rect<CoordT> {
+        (self.rect_x, self.rect_y, self.rect_w, self.rect_h)
+    }
+    /// The column or row of an offset from the top left corner of the grid
+    fn cell_of(&self, offset: f64, count: usize) -> usize {
+        //floats can round up to the next cell at the far edge
+        ((offset / self.cell_size.to_f64()) as usize).min(count - 1)
+    }
+    /// The index of the cell that contains the point, or None if it is outside the grid
+    fn cell_index(&self, x: CoordT, y: CoordT) -> Option<usize> {
+        if !rect::contains(self.bounds(), x, y) {
+            return None;
+        }
+        let column = self.cell_of((x - self.rect_x).to_f64(), self.columns);
+        let row = self.cell_of((y - self.rect_y).to_f64(), self.rows);
+        Some(row * self.columns + column)
+    }
+    /// Call f on every cell that overlaps the rect
+    fn for_each_cell_touching<'a>(&'a self, query: Rect<CoordT>, mut f: impl FnMut(&'a Cell<DataT, CoordT>)) {
+        if !rect::overlaps(self.bounds(), query) {
+            return;
+        }
+        //measured in f64 from the top left corner of the grid, so the far edges of the query can not overflow
+        let (start_x, start_y) = ((query.0.to_f64() - self.rect_x.to_f64()).max(0.0), (query.1.to_f64() - self.rect_y.to_f64()).max(0.0));
+        let (end_x, end_y) = (query.0.to_f64() - self.rect_x.to_f64() + query.2.to_f64(), query.1.to_f64() - self.rect_y.to_f64() + query.3.to_f64());
+        let columns = self.cell_of(start_x, self.columns)..=self.cell_of(end_x, self.columns);
+        for row in self.cell_of(start_y, self.rows)..=self.cell_of(end_y, self.rows) {
+            for column in columns.clone() {
+                f(&self.cells[row * self.columns + column]);
+            }
+        }
+    }
+    /// Insert a point into the correct cell, or return false if it doesn't fit
+    pub fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.try_insert(x, y, data).is_ok()
+    }
+    /// Insert a point into the correct cell. If it doesn't fit, the data is given back with the error, so
+    /// the caller can keep it or store it elsewhere.
+    pub fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        match self.cell_index(x, y) {
+            Some(index) => {
+                self.cells[index].data.push(data);
+                self.cells[index].positions.push((x, y));
+                Ok(())
+            }
+            None => Err((data, QuadTreeError::OutOfBounds { x, y, rect: self.bounds() })),
+        }
+    }
+    /// Remove the items stored at exactly (x, y) for which the predicate returns true, and return them.
+    /// Only the cell that owns the point is searched.
+    pub fn remove(&mut self, x: CoordT, y: CoordT, mut predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        let mut removed = Vec::new();
+        let Some(index) = self.cell_index(x, y) else { return removed };
+        let cell = &mut self.cells[index];
+        let mut i = 0;
+        while i < cell.data.len() {
+            if cell.positions[i] == (x, y) && predicate(&cell.data[i]) {
+                cell.positions.swap_remove(i);
+                removed.push(cell.data.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        removed
+    }
+    /// Return every item whose stored position is inside the rect. Only the cells that overlap the rect are visited.
+    pub fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        let query = (x, y, w, h);
+        let mut found = Vec::new();
+        self.for_each_cell_touching(query, |cell| {
+            for (item, &(item_x, item_y)) in cell.data.iter().zip(&cell.positions) {
+                if rect::contains(query, item_x, item_y) {
+                    found.push(item);
+                }
+            }
+        });
+        found
+    }
+    /// Return a reference to the vec of data of the cell that contains the point.
+    /// Panics if the point is outside the grid, see try_broad_phase.
+    pub fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.try_broad_phase(x, y).unwrap()
+    }
+    /// Return a mutable reference to the vec of data of the cell that contains the point.
+    /// Panics if the point is outside the grid, see try_broad_phase_mut.
+    pub fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.try_broad_phase_mut(x, y).unwrap()
+    }
+    /// Return a reference to the vec of data of the cell that contains the point, or OutOfBounds
+    pub fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        let index = self.cell_index(x, y).ok_or(QuadTreeError::OutOfBounds { x, y, rect: self.bounds() })?;
+        Ok(&self.cells[index].data)
+    }
+    /// Mutable version of try_broad_phase
+    pub fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        let index = self.cell_index(x, y).ok_or(QuadTreeError::OutOfBounds { x, y, rect: self.bounds() })?;
+        Ok(&mut self.cells[index].data)
+    }
+    /// Call f on the data of every cell, row by row. The cells are handed out as slices, so items can be changed
+    /// but not added or removed.
+    pub fn for_each_cell_mut(&mut self, mut f: impl FnMut(&mut [DataT])) {
+        for cell in self.cells.iter_mut() {
+            f(&mut cell.data);
+        }
+    }
+}
+
+/// The cells are square, the larger of the width and height of the builder halved once per level of its depth,
+/// and stop halving at 1 unit with integer coordinates. This is about the size of the leaves of a tree built from
+/// the same builder, but not the same split: the tree halves each axis on its own and gives odd units to the
+/// right and bottom quadrants, so a 100x100 world at depth 3 has 8 columns of leaves but 9 columns of cells.
+/// A world of size 0 gets a single cell of one unit, so building it does not trip the assert of new_empty.
+impl<DataT, CoordT: Coord> FromBuilder<DataT, CoordT> for Grid<DataT, CoordT> {
+    fn from_builder(builder: &QuadTreeBuilder<CoordT>) -> Self {
+        let mut cell_size = if builder.rect_w > builder.rect_h { builder.rect_w } else { builder.rect_h };
+        for _ in 0..builder.levels() {
+            //stop at 1 unit wide integer cells
+            if cell_size.half() <= CoordT::ZERO {
+                break;
+            }
+            cell_size = cell_size.half();
+        }
+        //a world without a width or height has nothing to divide, it gets a single cell of one unit
+        if cell_size <= CoordT::ZERO {
+            cell_size = CoordT::ONE;
+        }
+        let mut grid = Grid::new_empty(builder.rect_x, builder.rect_y, builder.rect_w, builder.rect_h, cell_size);
+        grid.reserve_cells(builder.leaf_capacity);
+        grid
+    }
+    fn insert_point(&mut self, x: CoordT, y: CoordT, data: DataT) {
+        self.insert(x, y, data);
+    }
+}
+
+impl<DataT, CoordT: Coord> SpatialIndex<DataT, CoordT> for Grid<DataT, CoordT> {
+    fn clear(&mut self) {
+        self.clear();
+    }
+    fn insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> bool {
+        self.insert(x, y, data)
+    }
+    fn try_insert(&mut self, x: CoordT, y: CoordT, data: DataT) -> Result<(), (DataT, QuadTreeError<CoordT>)> {
+        self.try_insert(x, y, data)
+    }
+    fn remove(&mut self, x: CoordT, y: CoordT, predicate: impl FnMut(&DataT) -> bool) -> Vec<DataT> {
+        self.remove(x, y, predicate)
+    }
+    fn broad_phase(&self, x: CoordT, y: CoordT) -> &Vec<DataT> {
+        self.broad_phase(x, y)
+    }
+    fn broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> &mut Vec<DataT> {
+        self.broad_phase_mut(x, y)
+    }
+    fn try_broad_phase(&self, x: CoordT, y: CoordT) -> Result<&Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase(x, y)
+    }
+    fn try_broad_phase_mut(&mut self, x: CoordT, y: CoordT) -> Result<&mut Vec<DataT>, QuadTreeError<CoordT>> {
+        self.try_broad_phase_mut(x, y)
+    }
+    fn query_rect(&self, x: CoordT, y: CoordT, w: CoordT, h: CoordT) -> Vec<&DataT> {
+        self.query_rect(x, y, w, h)
+    }
+}
+
+use super::{GetX, GetY};
+
+impl<Entity: GetX<CoordT>+GetY<CoordT>, CoordT: Coord> Grid<Entity, CoordT> {
+
+    /// Build a new Grid of references to the entities. The grid borrows the model, so the model can not
+    /// be dropped or changed while the grid is alive.
+    pub fn build_from_model(model: &mut [Entity], rect_x: CoordT, rect_y: CoordT, rect_w: CoordT, rect_h: CoordT, cell_size: CoordT) -> Grid<&mut Entity, CoordT> {
+        Grid::refill_from_model(Grid::new_empty(rect_x, rect_y, rect_w, rect_h, cell_size), model)
+    }
+
+    /// Fill an old grid with references to the entities of a new borrow of the model. The old grid is recycled,
+    /// so the capacities of its cells are kept.
+    pub fn refill_from_model<'a>(grid: Grid<&mut Entity, CoordT>, model: &'a mut [Entity]) -> Grid<&'a mut Entity, CoordT> {
+        let mut grid = grid.recycle();
+        for entity in model.iter_mut() {
+            grid.insert(entity.get_x(), entity.get_y(), entity);
+        }
+        grid
+    }
+
+    /// Fill the grid with references to the model, and call f with it. The grid is emptied again when f returns,
+    /// so it can be kept from frame to frame next to the model without borrowing it. If f panics, the grid is
+    /// left as a single empty cell covering no area.
+    pub fn with_grid<'a, R>(grid: &mut Grid<&mut Entity, CoordT>, model: &'a mut [Entity], f: impl FnOnce(&mut Grid<&'a mut Entity, CoordT>) -> R) -> R {
+        let empty = Grid::new_empty(CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, CoordT::ZERO, grid.cell_size);
+        let mut filled = Grid::refill_from_model(std::mem::replace(grid, empty), model);
+        let result = f(&mut filled);
+        *grid = filled.recycle();
+        result
+    }
+}
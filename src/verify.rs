@@ -0,0 +1,451 @@
+//! A brute force oracle for the backends. The collisions found with a backend are compared with the ones found
+//! by update_entity_global, which checks every entity against every other, on random models.
+
+use super::coord::Coord;
+use super::entity::*;
+use super::spatial_index::SpatialIndex;
+use super::{GetX, GetY};
+use rand::distributions::uniform::SampleUniform;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// An entity with its index in the model. The backends are checked with these owned items, so an entity can
+/// tell itself apart from the other candidates without comparing addresses.
+#[derive(Debug, Clone)]
+pub struct Indexed<CoordT: Coord = u16> {
+    pub index: usize,
+    pub entity: Entity<CoordT>,
+}
+
+impl<CoordT: Coord> GetX<CoordT> for Indexed<CoordT> {
+    fn get_x(&self) -> CoordT {
+        self.entity.x
+    }
+}
+
+impl<CoordT: Coord> GetY<CoordT> for Indexed<CoordT> {
+    fn get_y(&self) -> CoordT {
+        self.entity.y
+    }
+}
+
+/// An entity whose collision flag from a backend differs from the one set by update_entity_global
+#[derive(Debug, Clone)]
+pub struct Mismatch<CoordT: Coord = u16> {
+    /// The scenario the model was built for
+    pub config: ModelConfig<CoordT>,
+    /// The index of the entity in the model
+    pub index: usize,
+    /// The entity, with the collision flag set by update_entity_global
+    pub entity: Entity<CoordT>,
+}
+
+/// Set the collision flag of every entity of the model that collides with another one, using a backend that
+/// covers rect. The candidates of an entity are the entities in its neighbourhood, like in example_loop.
+pub fn update_with_index<Index: SpatialIndex<Indexed<CoordT>, CoordT>, CoordT: Coord>(model: &mut [Entity<CoordT>], rect: (CoordT, CoordT, CoordT, CoordT)) {
+    let mut index = Index::new(rect.0, rect.1, rect.2, rect.3);
+    index.rebuild(model.iter().cloned().enumerate().map(|(index, entity)| Indexed { index, entity }));
+    for (i, entity) in model.iter_mut().enumerate() {
+        let (x, y, w, h) = neighbourhood(entity);
+        if index.query_rect(x, y, w, h).into_iter().any(|other| other.index != i && is_coliding(entity, &other.entity)) {
+            entity.collision = true;
+        }
+    }
+}
+
+/// Set the collision flags with update_entity_global. Each entity is split off from the rest of the model,
+/// so no entity is borrowed twice.
+fn update_global<CoordT: Coord>(model: &mut [Entity<CoordT>]) {
+    for i in 0..model.len() {
+        let (before, rest) = model.split_at_mut(i);
+        let (entity, after) = rest.split_first_mut().unwrap();
+        update_entity_global(entity, before);
+        update_entity_global(entity, after);
+    }
+}
+
+/// Compare the collisions found with a backend and with update_entity_global for a model of the scenario.
+/// The backend covers the world of the scenario. Returns every entity where the two disagree.
+pub fn check_model<Index: SpatialIndex<Indexed<CoordT>, CoordT>, CoordT: Coord>(config: ModelConfig<CoordT>, model: &[Entity<CoordT>]) -> Vec<Mismatch<CoordT>> {
+    let (origin_x, origin_y) = config.world_origin;
+    let mut expected = model.to_vec();
+    update_global(&mut expected);
+    let mut found = model.to_vec();
+    update_with_index::<Index, CoordT>(&mut found, (origin_x, origin_y, config.world_size, config.world_size));
+    expected.into_iter().zip(found).enumerate()
+        .filter(|(_, (expected, found))| expected.collision != found.collision)
+        .map(|(index, (entity, _))| Mismatch { config, index, entity })
+        .collect()
+}
+
+/// Run check_model on a model built by init_model for the scenario
+pub fn check_config<Index: SpatialIndex<Indexed<CoordT>, CoordT>, CoordT: Coord + SampleUniform>(config: ModelConfig<CoordT>) -> Vec<Mismatch<CoordT>> {
+    check_model::<Index, CoordT>(config, &init_model(config))
+}
+
+/// Run check_model on random scenarios, with random model, world and entity sizes and world origins. Part of
+/// the entities are moved onto the borders between the leaves of a tree of the default depth, and next to the far
+/// edges of the world, where backends that disagree on who owns an edge lose collisions.
+/// Returns the mismatches of the first scenario that has any.
+pub fn check_random_scenarios<Index: SpatialIndex<Indexed>>(scenarios: usize, seed: u64) -> Result<(), Vec<Mismatch>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..scenarios {
+        let world_size = rng.gen_range(8..=512);
+        let config = ModelConfig {
+            model_size: rng.gen_range(1..=500),
+            world_size,
+            world_origin: (rng.gen_range(0..1000), rng.gen_range(0..1000)),
+            entity_size: rng.gen_range(0..=16),
+        };
+        let mut model = init_model(config);
+        let (origin_x, origin_y) = config.world_origin;
+        //the default depth of 3 gives an 8x8 grid of leaves, the far edges are outside the world so the last
+        //unit inside it is used instead
+        let border = |rng: &mut StdRng| (world_size * rng.gen_range(1..=8) / 8 - rng.gen_range(0..=1)).min(world_size - 1);
+        for entity in model.iter_mut() {
+            if rng.gen_bool(0.2) {
+                entity.x = origin_x + border(&mut rng);
+            }
+            if rng.gen_bool(0.2) {
+                entity.y = origin_y + border(&mut rng);
+            }
+        }
+        let mismatches = check_model::<Index, u16>(config, &model);
+        if !mismatches.is_empty() {
+            return Err(mismatches);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spatial_index::SpatialQueries;
+    use crate::{enum_quadtree, fixed_depth_quad_tree, loose_quadtree, morton_quadtree, uniform_grid};
+
+    /// Points of a signed world centred on (0, 0) that are so far from it that their offset from its origin
+    /// does not fit in an i32. They must be left out, not stored in the wrong leaf or overflow.
+    fn far_points_are_outside<Index: SpatialIndex<usize, i32>>() {
+        let mut index = Index::new(-64, -64, 128, 128);
+        for (x, y) in [(i32::MAX, 0), (0, i32::MAX), (i32::MIN, 0), (0, i32::MIN), (i32::MAX, i32::MIN)] {
+            assert!(!index.insert(x, y, 0), "({}, {}) was inserted", x, y);
+        }
+        assert!(index.insert(-10, -10, 1) && index.insert(63, 63, 2));
+        //the query reaches from the lowest i32 to just before -1, so it overlaps the world but not its far corner
+        let found: Vec<usize> = index.query_rect(i32::MIN, i32::MIN, i32::MAX, i32::MAX).into_iter().copied().collect();
+        assert_eq!(found, vec![1]);
+        assert!(index.query_rect(i32::MAX - 8, i32::MAX - 8, 8, 8).is_empty());
+    }
+
+    #[test]
+    fn fixed_depth_tree_matches_global() {
+        check_random_scenarios::<fixed_depth_quad_tree::QuadTree<_>>(50, 1).unwrap();
+    }
+
+    #[test]
+    fn enum_tree_matches_global() {
+        check_random_scenarios::<enum_quadtree::QuadTree<_>>(50, 2).unwrap();
+    }
+
+    #[test]
+    fn grid_matches_global() {
+        check_random_scenarios::<uniform_grid::Grid<_>>(50, 3).unwrap();
+    }
+
+    #[test]
+    fn loose_tree_matches_global() {
+        check_random_scenarios::<loose_quadtree::QuadTree<_>>(50, 4).unwrap();
+    }
+
+    #[test]
+    fn morton_tree_matches_global() {
+        check_random_scenarios::<morton_quadtree::QuadTree<_>>(50, 5).unwrap();
+    }
+
+    #[test]
+    fn morton_tree_leaves_own_their_float_borders() {
+        //with floats the leaves are size / 2^depth wide. Every point of these worlds is within a factor of two of
+        //the origin, or the origin is 0, so the offset of a point from the origin is exact and a point one step
+        //before a border stays before it
+        for (world_size, world_origin) in [(128.0, (128.0, 0.0)), (100.5, (100.5, 1000.25)), (32.0, (-128.0, -64.0)), (3.0, (0.0, 3.5))] {
+            let tree: morton_quadtree::QuadTree<usize, f64> = SpatialIndex::new(world_origin.0, world_origin.1, world_size, world_size);
+            let leaf_size = world_size / 8.0;
+            assert_eq!(tree.leaf_size(), leaf_size);
+            //a point on a border belongs to the leaf to the right of or below it, a point just before it does not
+            for column in 1..8 {
+                let offset = leaf_size * column as f64;
+                let (x, y) = (world_origin.0 + offset, world_origin.1 + offset);
+                let inside = tree.leaf_index(x + leaf_size / 2.0, y + leaf_size / 2.0);
+                assert_eq!(tree.leaf_index(x, y), inside, "{} in {:?}", offset, world_origin);
+                assert_ne!(tree.leaf_index(x.prev(), y), inside, "{} in {:?}", offset, world_origin);
+                assert_ne!(tree.leaf_index(x, y.prev()), inside, "{} in {:?}", offset, world_origin);
+            }
+        }
+    }
+
+    #[test]
+    fn morton_tree_matches_global_on_float_leaf_borders() {
+        for (world_size, world_origin) in [(128.0, (-64.0, -64.0)), (100.1, (-50.3, 7.7)), (1.0, (0.0, -0.5)), (37.3, (1000.9, -0.1))] {
+            let leaf_size = world_size / 8.0;
+            for entity_size in [0.0, 0.5, 4.0] {
+                let config = ModelConfig { model_size: 300, world_size, world_origin, entity_size };
+                let mut model = init_model(config);
+                //on the borders of the leaves and just before them
+                for (i, entity) in model.iter_mut().enumerate() {
+                    let offset = leaf_size * (i % 8) as f64;
+                    entity.x = world_origin.0 + if i % 3 == 0 { offset } else { offset.prev().max(0.0) };
+                    entity.y = world_origin.1 + leaf_size * (i % 5) as f64;
+                }
+                assert!(check_model::<morton_quadtree::QuadTree<_, f64>, f64>(config, &model).is_empty(), "{:?}", config);
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_depth_tree_matches_global_with_signed_coords() {
+        //worlds centred on (0, 0) and off it, with odd sizes that leave a remainder when halved
+        for (world_size, world_origin) in [(128, (-64, -64)), (100, (-50, -17)), (37, (-1000, 3)), (9, (-4, -4))] {
+            for entity_size in [0, 1, 5, 16] {
+                let config = ModelConfig { model_size: 300, world_size, world_origin, entity_size };
+                assert!(check_config::<fixed_depth_quad_tree::QuadTree<_, i32>, i32>(config).is_empty(), "{:?}", config);
+            }
+        }
+    }
+
+    #[test]
+    fn enum_tree_matches_global_with_float_coords() {
+        for (world_size, world_origin) in [(128.0, (-64.0, -64.0)), (100.5, (-50.25, 0.0)), (1.0, (0.0, -0.5))] {
+            for entity_size in [0.0, 0.5, 4.0, 16.0] {
+                let config = ModelConfig { model_size: 300, world_size, world_origin, entity_size };
+                assert!(check_config::<enum_quadtree::QuadTree<_, f32>, f32>(config).is_empty(), "{:?}", config);
+            }
+        }
+    }
+
+    #[test]
+    fn far_signed_points_are_outside_every_backend() {
+        far_points_are_outside::<fixed_depth_quad_tree::QuadTree<_, i32>>();
+        far_points_are_outside::<enum_quadtree::QuadTree<_, i32>>();
+        far_points_are_outside::<loose_quadtree::QuadTree<_, i32>>();
+        far_points_are_outside::<morton_quadtree::QuadTree<_, i32>>();
+        far_points_are_outside::<uniform_grid::Grid<_, i32>>();
+    }
+
+    /// The points of a 128x128 world at the origin, on a coarse grid that lands on the leaf borders and the far edges
+    fn wrapped_world_points() -> Vec<(u16, u16)> {
+        (0..128).step_by(3).flat_map(|x| (0..128).step_by(5).map(move |y| (x, y))).chain([(127, 127), (0, 127), (127, 0)]).collect()
+    }
+
+    /// Compare the wrapped queries of a tree filled with wrapped_world_points against a brute force search
+    fn check_wrapped_queries(query_rect: impl Fn(u16, u16, u16, u16) -> Vec<(u16, u16)>, query_radius: impl Fn(u16, u16, u16) -> Vec<(u16, u16)>) {
+        let points = wrapped_world_points();
+        let ahead = |from: u16, at: u16| (at as i32 - from as i32).rem_euclid(128);
+        let short = |a: u16, b: u16| { let d = (a as i32 - b as i32).abs(); d.min(128 - d) };
+        let sorted = |mut found: Vec<(u16, u16)>| { found.sort(); found };
+        //crossing the right edge, the bottom edge and both, starting inside and outside the world
+        for (x, y, w, h) in [(120, 10, 20, 8), (10, 120, 8, 20), (120, 124, 16, 8), (250, 250, 10, 10), (127, 127, 1, 1)] {
+            let expected: Vec<_> = points.iter().copied().filter(|&(px, py)| ahead(x % 128, px) < w as i32 && ahead(y % 128, py) < h as i32).collect();
+            assert_eq!(sorted(query_rect(x, y, w, h)), sorted(expected), "{:?}", (x, y, w, h));
+        }
+        //radii bigger than half the world reach every point from both sides
+        for (x, y, radius) in [(0, 0, 70), (127, 64, 90), (3, 125, 10), (64, 64, 200)] {
+            let expected: Vec<_> = points.iter().copied().filter(|&(px, py)| {
+                let (dx, dy) = (short(x, px), short(y, py));
+                dx * dx + dy * dy <= radius as i32 * radius as i32
+            }).collect();
+            assert_eq!(sorted(query_radius(x, y, radius)), sorted(expected), "{:?}", (x, y, radius));
+        }
+    }
+
+    #[test]
+    fn wrapped_queries_match_brute_force() {
+        let mut fixed: fixed_depth_quad_tree::QuadTree<(u16, u16)> = SpatialIndex::new(0, 0, 128, 128);
+        let mut enum_tree: enum_quadtree::QuadTree<(u16, u16)> = SpatialIndex::new(0, 0, 128, 128);
+        for (x, y) in wrapped_world_points() {
+            assert!(fixed.insert(x, y, (x, y)) && enum_tree.insert(x, y, (x, y)));
+        }
+        check_wrapped_queries(|x, y, w, h| fixed.query_rect_wrapped(x, y, w, h).into_iter().copied().collect(),
+            |x, y, radius| fixed.query_radius_wrapped(x, y, radius).into_iter().copied().collect());
+        check_wrapped_queries(|x, y, w, h| enum_tree.query_rect_wrapped(x, y, w, h).into_iter().copied().collect(),
+            |x, y, radius| enum_tree.query_radius_wrapped(x, y, radius).into_iter().copied().collect());
+    }
+
+    /// Random boxes of a 128x128 world, with boxes on the midlines that straddle the first split, and boxes that
+    /// hang over the far edges, which the trees keep at the root
+    fn pair_boxes() -> Vec<(u16, u16, u16, u16)> {
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut boxes: Vec<_> = (0..300).map(|_| (rng.gen_range(0..128), rng.gen_range(0..128), rng.gen_range(0..12), rng.gen_range(0..12))).collect();
+        boxes.extend([(60, 10, 8, 2), (62, 11, 4, 4), (10, 60, 3, 9), (60, 60, 8, 8), (64, 64, 0, 0), (63, 63, 1, 1)]);
+        boxes.extend([(120, 5, 20, 3), (5, 120, 3, 20), (126, 126, 10, 10), (100, 100, 40, 40), (0, 0, 128, 128)]);
+        boxes
+    }
+
+    /// Check pairs of ids against a brute force search of pair_boxes: every overlapping pair is there once, and
+    /// no pair is there twice
+    fn check_pairs(name: &str, mut pairs: Vec<(usize, usize)>) {
+        let boxes = pair_boxes();
+        //a box with no width or height is a single point
+        let overlap = |a: u16, a_len: u16, b: u16, b_len: u16| (a as u32) < b as u32 + b_len.max(1) as u32 && (b as u32) < a as u32 + a_len.max(1) as u32;
+        for pair in pairs.iter_mut() {
+            *pair = (pair.0.min(pair.1), pair.0.max(pair.1));
+        }
+        pairs.sort();
+        let twice = pairs.windows(2).find(|pair| pair[0] == pair[1]);
+        assert!(twice.is_none(), "{}: {:?} is reported twice", name, twice);
+        for (i, a) in boxes.iter().enumerate() {
+            for (j, b) in boxes.iter().enumerate().skip(i + 1) {
+                if overlap(a.0, a.2, b.0, b.2) && overlap(a.1, a.3, b.1, b.3) {
+                    assert!(pairs.binary_search(&(i, j)).is_ok(), "{}: {:?} and {:?} are missing", name, a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_overlapping_pair_is_reported_once() {
+        let mut fixed: fixed_depth_quad_tree::QuadTree<usize> = SpatialIndex::new(0, 0, 128, 128);
+        let mut enum_tree: enum_quadtree::QuadTree<usize> = SpatialIndex::new(0, 0, 128, 128);
+        let mut adaptive = enum_quadtree::QuadTree::new_adaptive(0, 0, 128, 128, 4, 5);
+        for (i, (x, y, w, h)) in pair_boxes().into_iter().enumerate() {
+            assert!(fixed.insert_box(x, y, w, h, i) && enum_tree.insert_box(x, y, w, h, i) && adaptive.insert_box(x, y, w, h, i));
+        }
+        check_pairs("fixed candidate_pairs", fixed.candidate_pairs().into_iter().map(|(&a, &b)| (a, b)).collect());
+        check_pairs("enum candidate_pairs", enum_tree.candidate_pairs().into_iter().map(|(&a, &b)| (a, b)).collect());
+        check_pairs("adaptive candidate_pairs", adaptive.candidate_pairs().into_iter().map(|(&a, &b)| (a, b)).collect());
+        let mut pairs = Vec::new();
+        fixed.for_each_pair_mut(|&mut a, &mut b| pairs.push((a, b)));
+        check_pairs("fixed for_each_pair_mut", pairs);
+        let mut pairs = Vec::new();
+        enum_tree.for_each_pair_mut(|&mut a, &mut b| pairs.push((a, b)));
+        check_pairs("enum for_each_pair_mut", pairs);
+        let mut pairs = Vec::new();
+        adaptive.for_each_pair_mut(|&mut a, &mut b| pairs.push((a, b)));
+        check_pairs("adaptive for_each_pair_mut", pairs);
+    }
+
+    /// Random points of a 128x128 world, with a ring of points at distance 5 from (64, 64), some of them on the
+    /// leaf borders, and a few points stored twice, so there are ties
+    fn nearest_points() -> Vec<(u16, u16)> {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut points: Vec<_> = (0..200).map(|_| (rng.gen_range(0..128), rng.gen_range(0..128))).collect();
+        points.extend([(69, 64), (59, 64), (64, 69), (64, 59), (67, 68), (61, 60), (68, 61), (60, 67)]);
+        points.extend([(69, 64), (61, 60), (0, 0), (0, 0), (127, 127)]);
+        points
+    }
+
+    fn distance_squared((ax, ay): (u16, u16), (bx, by): (u16, u16)) -> f64 {
+        let (dx, dy) = (ax as f64 - bx as f64, ay as f64 - by as f64);
+        dx * dx + dy * dy
+    }
+
+    /// Compare k_nearest of a tree filled with nearest_points against sorting every point by its distance. Tied
+    /// points can come back in any order, so the distances are compared, and every point must be a stored one
+    /// at the distance it is reported at.
+    fn check_k_nearest(name: &str, k_nearest: impl Fn(u16, u16, usize) -> Vec<(f64, (u16, u16))>) {
+        let points = nearest_points();
+        //the query points outside the root are closest to its edges and corners
+        for (x, y) in [(64, 64), (0, 0), (127, 127), (63, 70), (200, 10), (10, 300), (400, 400)] {
+            let mut expected: Vec<f64> = points.iter().map(|&point| distance_squared((x, y), point)).collect();
+            expected.sort_by(f64::total_cmp);
+            for k in [0, 1, 3, 9, 12, 50, points.len(), points.len() + 10] {
+                let found = k_nearest(x, y, k);
+                let distances: Vec<f64> = found.iter().map(|&(d2, _)| d2).collect();
+                assert_eq!(distances, expected[..k.min(points.len())], "{}: {:?} k = {}", name, (x, y), k);
+                let mut left = points.clone();
+                for &(d2, point) in &found {
+                    assert_eq!(d2, distance_squared((x, y), point), "{}: {:?} k = {}", name, (x, y), k);
+                    let index = left.iter().position(|&stored| stored == point);
+                    assert!(index.is_some(), "{}: {:?} is reported more often than it is stored", name, point);
+                    left.swap_remove(index.unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn k_nearest_matches_brute_force() {
+        let mut fixed: fixed_depth_quad_tree::QuadTree<(u16, u16)> = SpatialIndex::new(0, 0, 128, 128);
+        let mut enum_tree: enum_quadtree::QuadTree<(u16, u16)> = SpatialIndex::new(0, 0, 128, 128);
+        let mut adaptive = enum_quadtree::QuadTree::new_adaptive(0, 0, 128, 128, 4, 5);
+        for (x, y) in nearest_points() {
+            assert!(fixed.insert(x, y, (x, y)) && enum_tree.insert(x, y, (x, y)) && adaptive.insert(x, y, (x, y)));
+        }
+        check_k_nearest("fixed", |x, y, k| fixed.k_nearest(x, y, k).into_iter().map(|(d2, &point)| (d2, point)).collect());
+        check_k_nearest("enum", |x, y, k| enum_tree.k_nearest(x, y, k).into_iter().map(|(d2, &point)| (d2, point)).collect());
+        check_k_nearest("adaptive", |x, y, k| adaptive.k_nearest(x, y, k).into_iter().map(|(d2, &point)| (d2, point)).collect());
+        let closest = nearest_points().into_iter().map(|point| distance_squared((66, 66), point)).min_by(f64::total_cmp);
+        assert_eq!(fixed.nearest(66, 66).map(|(d2, _)| d2), closest);
+        assert_eq!(enum_tree.nearest(66, 66).map(|(d2, _)| d2), closest);
+        let empty: enum_quadtree::QuadTree<(u16, u16)> = SpatialIndex::new(0, 0, 128, 128);
+        assert!(empty.nearest(64, 64).is_none() && empty.k_nearest(64, 64, 5).is_empty());
+    }
+
+    /// Compare query_radius of a tree filled with nearest_points against a brute force search. The ring around
+    /// (64, 64) lies exactly on a radius of 5, and the other circles reach past the edges of the root.
+    fn check_query_radius(name: &str, query_radius: impl Fn(u16, u16, u16) -> Vec<(u16, u16)>) {
+        let points = nearest_points();
+        for (x, y, radius) in [(64, 64, 5), (64, 64, 4), (66, 62, 0), (0, 0, 0), (2, 2, 20), (120, 126, 30), (200, 64, 80), (64, 64, 300)] {
+            let mut expected: Vec<_> = points.iter().copied().filter(|&point| distance_squared((x, y), point) <= radius as f64 * radius as f64).collect();
+            expected.sort();
+            let mut found = query_radius(x, y, radius);
+            found.sort();
+            assert_eq!(found, expected, "{}: {:?}", name, (x, y, radius));
+        }
+    }
+
+    #[test]
+    fn query_radius_matches_brute_force() {
+        let mut fixed: fixed_depth_quad_tree::QuadTree<(u16, u16)> = SpatialIndex::new(0, 0, 128, 128);
+        let mut enum_tree: enum_quadtree::QuadTree<(u16, u16)> = SpatialIndex::new(0, 0, 128, 128);
+        let mut adaptive = enum_quadtree::QuadTree::new_adaptive(0, 0, 128, 128, 4, 5);
+        for (x, y) in nearest_points() {
+            assert!(fixed.insert(x, y, (x, y)) && enum_tree.insert(x, y, (x, y)) && adaptive.insert(x, y, (x, y)));
+        }
+        //the ring is on the circle, so it is found with a radius of 5 and not with 4
+        let on_circle = |radius| fixed.query_radius(64, 64, radius).into_iter().filter(|&&point| distance_squared((64, 64), point) == 25.0).count();
+        assert_eq!((on_circle(5), on_circle(4)), (10, 0));
+        check_query_radius("fixed", |x, y, radius| fixed.query_radius(x, y, radius).into_iter().copied().collect());
+        check_query_radius("enum", |x, y, radius| enum_tree.query_radius(x, y, radius).into_iter().copied().collect());
+        check_query_radius("adaptive", |x, y, radius| adaptive.query_radius(x, y, radius).into_iter().copied().collect());
+    }
+
+    /// Pairs of colliding entities on either side of the leaf borders at 16 and 64 of a 128x128 world with
+    /// 16x16 leaves, across the x border, the y border and a corner
+    fn border_entities() -> Vec<Entity> {
+        let entity = |x, y, size| Entity { x, y, width: size, height: size, collision: false };
+        vec![entity(14, 20, 4), entity(16, 20, 4), entity(40, 15, 2), entity(40, 16, 2), entity(63, 63, 2), entity(64, 64, 2), entity(100, 100, 3)]
+    }
+
+    /// Check that the collisions found through broad_phase_neighbourhood and neighbourhood match a brute force search
+    fn check_border_collisions<Index: SpatialQueries<usize, u16>>(name: &str) {
+        let mut model = border_entities();
+        let mut index = Index::new(0, 0, 128, 128);
+        for (i, entity) in model.iter().enumerate() {
+            assert!(index.insert(entity.get_x(), entity.get_y(), i));
+        }
+        for i in 0..model.len() {
+            let (x, y, w, h) = neighbourhood(&model[i]);
+            let mut candidates: Vec<usize> = index.broad_phase_neighbourhood(x, y, w, h).into_iter().copied().collect();
+            candidates.sort();
+            assert!(candidates.windows(2).all(|pair| pair[0] != pair[1]), "{}: {:?} are returned twice", name, candidates);
+            update_entity_indexed(&mut model, i, candidates);
+        }
+        let expected: Vec<bool> = model.iter().enumerate().map(|(i, entity)| {
+            model.iter().enumerate().any(|(j, other_entity)| i != j && is_coliding(entity, other_entity))
+        }).collect();
+        let collisions: Vec<bool> = model.iter().map(|entity| entity.collision).collect();
+        assert_eq!(collisions, expected, "{}", name);
+        assert_eq!(collisions, [true, true, true, true, true, true, false], "{}", name);
+    }
+
+    #[test]
+    fn neighbourhood_finds_collisions_across_leaf_borders() {
+        //the entities at x=14 and x=16 are in different leaves, so the leaf of one of them alone misses the other
+        let mut fixed: fixed_depth_quad_tree::QuadTree<usize> = SpatialIndex::new(0, 0, 128, 128);
+        let model = border_entities();
+        assert!(fixed.insert(model[0].x, model[0].y, 0) && fixed.insert(model[1].x, model[1].y, 1));
+        assert_eq!(fixed.broad_phase(model[1].x, model[1].y), &vec![1]);
+        check_border_collisions::<fixed_depth_quad_tree::QuadTree<usize>>("fixed");
+        check_border_collisions::<enum_quadtree::QuadTree<usize>>("enum");
+    }
+}